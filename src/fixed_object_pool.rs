@@ -0,0 +1,259 @@
+use std::sync::Arc;
+use std::ops::{Deref, DerefMut};
+use notify_future::NotifyFuture;
+use crate::sync::{LockRecover, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolError {
+    // all of the pool's waiter slots are taken
+    WaitersFull,
+    // no pool is registered under the requested name
+    NotFound,
+    // `close` was called on the pool
+    Closed,
+    // the pool already has its `max_capacity` objects
+    Full,
+}
+
+impl std::fmt::Display for PoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PoolError::WaitersFull => write!(f, "too many waiters queued for the pool"),
+            PoolError::NotFound => write!(f, "no pool is registered under the name"),
+            PoolError::Closed => write!(f, "the pool is closed"),
+            PoolError::Full => write!(f, "the pool can't take more objects"),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+struct FixedWaiter<T> {
+    waiter_id: u64,
+    future: NotifyFuture<()>,
+    // the object released to the waiter, until it wakes up to collect it
+    obj: Option<T>,
+}
+
+struct FixedObjectPoolState<T, const N: usize, const W: usize> {
+    free_list: [Option<T>; N],
+    waiter_list: [Option<FixedWaiter<T>>; W],
+    waiter_seq: u64,
+}
+
+impl <T, const N: usize, const W: usize> FixedObjectPoolState<T, N, W> {
+    fn find_waiter(&mut self, waiter_id: u64) -> Option<&mut Option<FixedWaiter<T>>> {
+        self.waiter_list.iter_mut().find(|slot| slot.as_ref().is_some_and(|waiter| waiter.waiter_id == waiter_id))
+    }
+}
+
+// An `ObjectPool` of `N` objects whose state has a fixed size: the objects and up to `W` waiters live in
+// arrays, a `get` that finds every waiter slot taken fails with `PoolError::WaitersFull` instead of
+// queueing. Waiters are served in FIFO order like the `ObjectPool` ones.
+pub struct FixedObjectPool<T, const N: usize, const W: usize = N> {
+    state: Arc<Mutex<FixedObjectPoolState<T, N, W>>>,
+}
+
+impl <T, const N: usize, const W: usize> Clone for FixedObjectPool<T, N, W> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone()
+        }
+    }
+}
+
+impl <T, const N: usize, const W: usize> std::fmt::Debug for FixedObjectPool<T, N, W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock_recover();
+        f.debug_struct("FixedObjectPool")
+            .field("free", &state.free_list.iter().filter(|obj| obj.is_some()).count())
+            .field("waiting", &state.waiter_list.iter().filter(|waiter| waiter.is_some()).count())
+            .finish()
+    }
+}
+
+impl <T, const N: usize, const W: usize> FixedObjectPool<T, N, W> {
+    pub fn new(objs: [T; N]) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(FixedObjectPoolState {
+                free_list: objs.map(Some),
+                waiter_list: std::array::from_fn(|_| None),
+                waiter_seq: 0,
+            }))
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.state.lock_recover().free_list.iter().filter(|obj| obj.is_some()).count()
+    }
+
+    pub fn try_get(&self) -> Option<FixedObjectGuard<T, N, W>> {
+        let obj = self.state.lock_recover().free_list.iter_mut().find_map(Option::take)?;
+        Some(FixedObjectGuard::new(self.clone(), obj))
+    }
+
+    pub async fn get(&self) -> Result<FixedObjectGuard<T, N, W>, PoolError> {
+        let waiter = {
+            let mut state = self.state.lock_recover();
+            if let Some(obj) = state.free_list.iter_mut().find_map(Option::take) {
+                return Ok(FixedObjectGuard::new(self.clone(), obj));
+            }
+            let waiter_id = state.waiter_seq;
+            let slot = state.waiter_list.iter_mut().find(|slot| slot.is_none()).ok_or(PoolError::WaitersFull)?;
+            let future = NotifyFuture::new();
+            *slot = Some(FixedWaiter {
+                waiter_id,
+                future: future.clone(),
+                obj: None,
+            });
+            state.waiter_seq += 1;
+            PendingFixedObject {
+                pool: self,
+                waiter_id,
+                future,
+                acquired: false,
+            }
+        };
+        let obj = waiter.wait().await;
+        Ok(FixedObjectGuard::new(self.clone(), obj))
+    }
+
+    fn release(&self, obj: T) {
+        let mut state = self.state.lock_recover();
+        // the oldest waiter that has no object yet
+        let next = state.waiter_list.iter_mut()
+            .filter_map(|slot| slot.as_mut())
+            .filter(|waiter| waiter.obj.is_none())
+            .min_by_key(|waiter| waiter.waiter_id);
+        if let Some(waiter) = next {
+            waiter.obj = Some(obj);
+            waiter.future.set_complete(());
+            return;
+        }
+        let slot = state.free_list.iter_mut().find(|slot| slot.is_none()).expect("more objects released than the pool holds");
+        *slot = Some(obj);
+    }
+
+    // A dropped waiter frees its slot, and gives back the object that was already handed to it.
+    fn cancel_wait(&self, waiter_id: u64) {
+        let mut state = self.state.lock_recover();
+        let obj = state.find_waiter(waiter_id).and_then(|slot| slot.take()).and_then(|waiter| waiter.obj);
+        drop(state);
+        if let Some(obj) = obj {
+            self.release(obj);
+        }
+    }
+}
+
+struct PendingFixedObject<'a, T, const N: usize, const W: usize> {
+    pool: &'a FixedObjectPool<T, N, W>,
+    waiter_id: u64,
+    future: NotifyFuture<()>,
+    acquired: bool,
+}
+
+impl <'a, T, const N: usize, const W: usize> PendingFixedObject<'a, T, N, W> {
+    async fn wait(mut self) -> T {
+        self.future.clone().await;
+        self.acquired = true;
+        let mut state = self.pool.state.lock_recover();
+        let waiter = state.find_waiter(self.waiter_id).and_then(|slot| slot.take()).unwrap();
+        waiter.obj.unwrap()
+    }
+}
+
+impl <'a, T, const N: usize, const W: usize> Drop for PendingFixedObject<'a, T, N, W> {
+    fn drop(&mut self) {
+        if !self.acquired {
+            self.pool.cancel_wait(self.waiter_id);
+        }
+    }
+}
+
+pub struct FixedObjectGuard<T, const N: usize, const W: usize = N> {
+    pool: FixedObjectPool<T, N, W>,
+    obj: Option<T>,
+}
+
+impl <T, const N: usize, const W: usize> FixedObjectGuard<T, N, W> {
+    fn new(pool: FixedObjectPool<T, N, W>, obj: T) -> Self {
+        Self {
+            pool,
+            obj: Some(obj)
+        }
+    }
+}
+
+impl <T, const N: usize, const W: usize> std::fmt::Debug for FixedObjectGuard<T, N, W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FixedObjectGuard").field("has_object", &self.obj.is_some()).finish()
+    }
+}
+
+impl <T, const N: usize, const W: usize> Deref for FixedObjectGuard<T, N, W> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.obj.as_ref().unwrap()
+    }
+}
+
+impl <T, const N: usize, const W: usize> DerefMut for FixedObjectGuard<T, N, W> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.obj.as_mut().unwrap()
+    }
+}
+
+impl <T, const N: usize, const W: usize> Drop for FixedObjectGuard<T, N, W> {
+    fn drop(&mut self) {
+        if let Some(obj) = self.obj.take() {
+            self.pool.release(obj);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::{FixedObjectPool, PoolError};
+
+    #[test]
+    fn test_fixed_pool() {
+        async_std::task::block_on(async {
+            let pool: FixedObjectPool<u32, 2> = FixedObjectPool::new([1, 2]);
+            let obj1 = pool.get().await.unwrap();
+            let obj2 = pool.get().await.unwrap();
+            assert!(pool.try_get().is_none());
+            let mut waiters = Vec::new();
+            for _ in 0..2 {
+                let pool = pool.clone();
+                // the guard is handed back, so one waiter can't finish and pass its object on to the other
+                waiters.push(async_std::task::spawn(async move { pool.get().await.unwrap() }));
+            }
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(format!("{:?}", pool), "FixedObjectPool { free: 0, waiting: 2 }");
+            // both waiter slots are taken
+            assert_eq!(pool.get().await.unwrap_err(), PoolError::WaitersFull);
+
+            drop(obj1);
+            drop(obj2);
+            let mut guards = Vec::new();
+            for waiter in waiters {
+                guards.push(waiter.await);
+            }
+            let mut got: Vec<u32> = guards.iter().map(|guard| **guard).collect();
+            got.sort();
+            assert_eq!(got, vec![1, 2]);
+            drop(guards);
+            assert_eq!(pool.available(), 2);
+
+            // a cancelled waiter frees its slot and doesn't swallow the object
+            let obj1 = pool.get().await.unwrap();
+            let _obj2 = pool.get().await.unwrap();
+            assert!(async_std::future::timeout(Duration::from_millis(50), pool.get()).await.is_err());
+            assert_eq!(format!("{:?}", pool), "FixedObjectPool { free: 0, waiting: 0 }");
+            drop(obj1);
+            assert!(async_std::future::timeout(Duration::from_secs(1), pool.get()).await.is_ok());
+        });
+    }
+}
@@ -1,3 +1,17 @@
+mod fixed_object_pool;
+mod named_barrier;
+mod named_mutex;
+mod named_wait_group;
 mod object_locker;
+mod object_pool;
+mod object_registry;
+mod sync;
+mod timer;
 
+pub use fixed_object_pool::*;
+pub use named_barrier::*;
+pub use named_mutex::*;
+pub use named_wait_group::*;
 pub use object_locker::*;
+pub use object_pool::*;
+pub use object_registry::*;
@@ -0,0 +1,174 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use notify_future::NotifyFuture;
+use crate::sync::{LockRecover, Mutex};
+
+struct BarrierWaiter {
+    waiter_id: u64,
+    future: NotifyFuture<()>,
+}
+
+struct NamedBarrierState<K> {
+    // the participants waiting at each name, a name is forgotten once its barrier releases
+    waiter_map: Mutex<HashMap<K, Vec<BarrierWaiter>>>,
+    waiter_seq: AtomicU64,
+}
+
+// Tasks arriving at the same name wait until `n` of them are there, then all of them go on together.
+// After that the name starts over, the next `n` arrivals form a new barrier. Clones share the barriers.
+pub struct NamedBarrier<K: Hash + Eq + Clone + Debug = String> {
+    state: Arc<NamedBarrierState<K>>,
+}
+
+impl <K: Hash + Eq + Clone + Debug> Clone for NamedBarrier<K> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone()
+        }
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug> Debug for NamedBarrier<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NamedBarrier")
+            .field("waiting", &self.state.waiter_map.lock_recover().len())
+            .finish()
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug> Default for NamedBarrier<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug> NamedBarrier<K> {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(NamedBarrierState {
+                waiter_map: Mutex::new(HashMap::new()),
+                waiter_seq: AtomicU64::new(0),
+            })
+        }
+    }
+
+    // Returns once `n` participants, this one included, have arrived at `name`. All participants of a
+    // name are expected to pass the same `n`, the arriving one decides whether the barrier is complete.
+    pub async fn arrive(&self, name: impl Into<K>, n: usize) {
+        let name = name.into();
+        let waiter = {
+            let mut waiter_map = self.state.waiter_map.lock_recover();
+            let waiters = waiter_map.entry(name.clone()).or_default();
+            if waiters.len() + 1 >= n {
+                for waiter in waiter_map.remove(&name).unwrap() {
+                    waiter.future.set_complete(());
+                }
+                return;
+            }
+            let waiter_id = self.state.waiter_seq.fetch_add(1, Ordering::Relaxed);
+            let future = NotifyFuture::new();
+            waiters.push(BarrierWaiter {
+                waiter_id,
+                future: future.clone(),
+            });
+            PendingArrival {
+                barrier: self,
+                name,
+                waiter_id,
+                future,
+                released: false,
+            }
+        };
+        waiter.wait().await
+    }
+
+    // A participant dropped while waiting no longer counts as arrived.
+    fn cancel_wait(&self, name: &K, waiter_id: u64) {
+        let mut waiter_map = self.state.waiter_map.lock_recover();
+        if let Some(waiters) = waiter_map.get_mut(name) {
+            waiters.retain(|waiter| waiter.waiter_id != waiter_id);
+            if waiters.is_empty() {
+                waiter_map.remove(name);
+            }
+        }
+    }
+}
+
+struct PendingArrival<'a, K: Hash + Eq + Clone + Debug> {
+    barrier: &'a NamedBarrier<K>,
+    name: K,
+    waiter_id: u64,
+    future: NotifyFuture<()>,
+    released: bool,
+}
+
+impl <'a, K: Hash + Eq + Clone + Debug> PendingArrival<'a, K> {
+    async fn wait(mut self) {
+        self.future.clone().await;
+        self.released = true;
+    }
+}
+
+impl <'a, K: Hash + Eq + Clone + Debug> Drop for PendingArrival<'a, K> {
+    fn drop(&mut self) {
+        if !self.released {
+            self.barrier.cancel_wait(&self.name, self.waiter_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use crate::NamedBarrier;
+    use crate::sync::{assert_send, assert_send_sync};
+
+    #[test]
+    fn test_named_barrier() {
+        async_std::task::block_on(async {
+            let barrier: NamedBarrier = NamedBarrier::new();
+            let arrived = Arc::new(AtomicUsize::new(0));
+            let mut tasks = Vec::new();
+            for i in 0..3 {
+                let barrier = barrier.clone();
+                let arrived = arrived.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    async_std::task::sleep(Duration::from_millis(50 * i)).await;
+                    arrived.fetch_add(1, Ordering::SeqCst);
+                    barrier.arrive("test_named_barrier", 3).await;
+                    // nobody gets through before everyone arrived
+                    arrived.load(Ordering::SeqCst)
+                }));
+            }
+            for task in tasks {
+                assert_eq!(task.await, 3);
+            }
+            assert_eq!(format!("{:?}", barrier), "NamedBarrier { waiting: 0 }");
+
+            // a participant that gave up doesn't count
+            let cancelled = async_std::future::timeout(Duration::from_millis(50), barrier.arrive("test_named_barrier", 2)).await;
+            assert!(cancelled.is_err());
+            let waiter = {
+                let barrier = barrier.clone();
+                async_std::task::spawn(async move { barrier.arrive("test_named_barrier", 2).await })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(format!("{:?}", barrier), "NamedBarrier { waiting: 1 }");
+            barrier.arrive("test_named_barrier", 2).await;
+            waiter.await;
+        });
+    }
+
+    #[test]
+    fn test_auto_traits() {
+        assert_send_sync::<NamedBarrier>();
+        // the future of `arrive` can be spawned on multi-threaded runtimes
+        let barrier: NamedBarrier = NamedBarrier::new();
+        assert_send(&barrier.arrive("test_auto_traits", 1));
+    }
+}
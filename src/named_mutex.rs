@@ -0,0 +1,220 @@
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
+use crate::{Locker, LockerManager};
+use crate::sync::{LockRecover, Mutex};
+
+// A keyed mutex over values: every `lock` of an id gets the same `T`, created with `T::default()` the
+// first time the id is locked. Unlike `GuardObject` the value lives in the mutex, so all code paths using
+// an id see the same one. Clones share the values. A value stays in the mutex until it is removed with
+// `remove` or `NamedMutexGuard::remove`, so a mutex over ever new ids grows with them unless they are.
+pub struct NamedMutex<T, K: Hash + Eq + Clone + Debug = String> {
+    manager: LockerManager<K>,
+    values: Arc<Mutex<HashMap<K, T>>>,
+}
+
+impl <T, K: Hash + Eq + Clone + Debug> Clone for NamedMutex<T, K> {
+    fn clone(&self) -> Self {
+        Self {
+            manager: self.manager.clone(),
+            values: self.values.clone(),
+        }
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug> Debug for NamedMutex<T, K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NamedMutex")
+            .field("values", &self.values.lock_recover().len())
+            .finish()
+    }
+}
+
+impl <T: Default, K: Hash + Eq + Clone + Debug> Default for NamedMutex<T, K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <T: Default, K: Hash + Eq + Clone + Debug> NamedMutex<T, K> {
+    pub fn new() -> Self {
+        Self {
+            manager: LockerManager::new(),
+            values: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn lock(&self, id: impl Into<K>) -> NamedMutexGuard<T, K> {
+        let locker = self.manager.get_locker(id).await;
+        // the locker makes the guard the only user of the value, so it can take it out of the map
+        let value = self.values.lock_recover().remove(locker.id());
+        // `T::default()` is the caller's code, it runs after the map is unlocked
+        let value = value.unwrap_or_default();
+        NamedMutexGuard {
+            values: self.values.clone(),
+            locker,
+            value: Some(value),
+        }
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug> NamedMutex<T, K> {
+    // Waits for the id like `lock`, then takes its value out of the mutex. The next `lock` of the id
+    // starts over from `T::default()`.
+    pub async fn remove(&self, id: impl Into<K>) -> Option<T> {
+        let locker = self.manager.get_locker(id).await;
+        self.values.lock_recover().remove(locker.id())
+    }
+
+    // The ids that have a value.
+    pub fn len(&self) -> usize {
+        self.values.lock_recover().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// The value goes back into the mutex before the locker is released, so the next holder finds it.
+pub struct NamedMutexGuard<T, K: Hash + Eq + Clone + Debug = String> {
+    values: Arc<Mutex<HashMap<K, T>>>,
+    locker: Locker<K>,
+    value: Option<T>,
+}
+
+impl <T, K: Hash + Eq + Clone + Debug> NamedMutexGuard<T, K> {
+    pub fn id(&self) -> &K {
+        self.locker.id()
+    }
+
+    // Takes the value out for good, e.g. once a counter is back to zero. The locker is still released
+    // after it, and the next `lock` of the id gets `T::default()`.
+    pub fn remove(mut self) -> T {
+        self.value.take().unwrap()
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug> Debug for NamedMutexGuard<T, K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NamedMutexGuard").field("id", self.locker.id()).finish()
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug> Deref for NamedMutexGuard<T, K> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug> DerefMut for NamedMutexGuard<T, K> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug> Drop for NamedMutexGuard<T, K> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            let id = self.locker.id().clone();
+            self.values.lock_recover().insert(id, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::NamedMutex;
+    use crate::sync::assert_send_sync;
+
+    #[test]
+    fn test_named_mutex() {
+        async_std::task::block_on(async {
+            let counters: NamedMutex<u32> = NamedMutex::new();
+            let mut tasks = Vec::new();
+            for _ in 0..2 {
+                let counters = counters.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    for _ in 0..5 {
+                        let mut counter = counters.lock("test_named_mutex").await;
+                        let value = *counter;
+                        async_std::task::sleep(Duration::from_millis(10)).await;
+                        *counter = value + 1;
+                    }
+                }));
+            }
+            for task in tasks {
+                task.await;
+            }
+            assert_eq!(*counters.lock("test_named_mutex").await, 10);
+            assert_eq!(*counters.lock("test_named_mutex_other").await, 0);
+
+            let counter = counters.lock("test_named_mutex").await;
+            assert_eq!(format!("{:?}", counter), "NamedMutexGuard { id: \"test_named_mutex\" }");
+            assert_eq!(format!("{:?}", counters), "NamedMutex { values: 1 }");
+        });
+    }
+
+    #[test]
+    fn test_remove() {
+        async_std::task::block_on(async {
+            let counters: NamedMutex<u32> = NamedMutex::new();
+            *counters.lock("test_remove").await += 2;
+            *counters.lock("test_remove_other").await += 3;
+            assert_eq!(counters.len(), 2);
+
+            let counter = counters.lock("test_remove").await;
+            let remover = {
+                let counters = counters.clone();
+                async_std::task::spawn(async move { counters.remove("test_remove").await })
+            };
+            // the removal waits for the holder
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(counters.len(), 1);
+            drop(counter);
+            assert_eq!(remover.await, Some(2));
+            assert_eq!(counters.remove("test_remove").await, None);
+            assert_eq!(*counters.lock("test_remove").await, 0);
+
+            assert_eq!(counters.lock("test_remove_other").await.remove(), 3);
+            assert_eq!(counters.len(), 1);
+            counters.remove("test_remove").await;
+            assert!(counters.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_default_outside_lock() {
+        struct Probe {
+            map_was_free: bool,
+        }
+        lazy_static::lazy_static! {
+            static ref PROBED: NamedMutex<Probe> = NamedMutex::new();
+        }
+        impl Default for Probe {
+            fn default() -> Self {
+                #[cfg(not(feature = "parking_lot"))]
+                let map_was_free = PROBED.values.try_lock().is_ok();
+                #[cfg(feature = "parking_lot")]
+                let map_was_free = PROBED.values.try_lock().is_some();
+                Self {
+                    map_was_free
+                }
+            }
+        }
+        async_std::task::block_on(async {
+            assert!(PROBED.lock("test_default_outside_lock").await.map_was_free);
+        });
+    }
+
+    #[test]
+    fn test_auto_traits() {
+        assert_send_sync::<NamedMutex<Vec<u8>>>();
+        assert_send_sync::<crate::NamedMutexGuard<Vec<u8>>>();
+    }
+}
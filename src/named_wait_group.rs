@@ -0,0 +1,209 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use notify_future::NotifyFuture;
+use crate::sync::{LockRecover, Mutex};
+
+struct GroupWaiter {
+    waiter_id: u64,
+    future: NotifyFuture<()>,
+}
+
+#[derive(Default)]
+struct WaitGroup {
+    count: usize,
+    waiters: Vec<GroupWaiter>,
+}
+
+struct NamedWaitGroupState<K> {
+    // the groups with guards still out, a name is forgotten once its last guard drops
+    group_map: Mutex<HashMap<K, WaitGroup>>,
+    waiter_seq: AtomicU64,
+}
+
+// Go's WaitGroup by name: every `add` returns a guard, `wait` returns once all guards of the name have
+// dropped. Clones share the groups, and the guards are 'static so they can move into spawned workers.
+pub struct NamedWaitGroup<K: Hash + Eq + Clone + Debug = String> {
+    state: Arc<NamedWaitGroupState<K>>,
+}
+
+impl <K: Hash + Eq + Clone + Debug> Clone for NamedWaitGroup<K> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone()
+        }
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug> Debug for NamedWaitGroup<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NamedWaitGroup")
+            .field("groups", &self.state.group_map.lock_recover().len())
+            .finish()
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug> Default for NamedWaitGroup<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug> NamedWaitGroup<K> {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(NamedWaitGroupState {
+                group_map: Mutex::new(HashMap::new()),
+                waiter_seq: AtomicU64::new(0),
+            })
+        }
+    }
+
+    pub fn add(&self, name: impl Into<K>) -> WaitGroupGuard<K> {
+        let name = name.into();
+        self.state.group_map.lock_recover().entry(name.clone()).or_default().count += 1;
+        WaitGroupGuard {
+            group: self.clone(),
+            name,
+        }
+    }
+
+    // The guards of `name` still out, 0 once the group is done.
+    pub fn count(&self, name: &K) -> usize {
+        self.state.group_map.lock_recover().get(name).map_or(0, |group| group.count)
+    }
+
+    // Returns once no guard of `name` is left, right away if none was added. Guards added while it
+    // waits are waited for as well.
+    pub async fn wait(&self, name: impl Into<K>) {
+        let name = name.into();
+        let waiter = {
+            let mut group_map = self.state.group_map.lock_recover();
+            let Some(group) = group_map.get_mut(&name) else {
+                return;
+            };
+            let waiter_id = self.state.waiter_seq.fetch_add(1, Ordering::Relaxed);
+            let future = NotifyFuture::new();
+            group.waiters.push(GroupWaiter {
+                waiter_id,
+                future: future.clone(),
+            });
+            PendingGroup {
+                group: self,
+                name,
+                waiter_id,
+                future,
+                released: false,
+            }
+        };
+        waiter.wait().await
+    }
+
+    fn done(&self, name: &K) {
+        let mut group_map = self.state.group_map.lock_recover();
+        let group = group_map.get_mut(name).unwrap();
+        group.count -= 1;
+        if group.count == 0 {
+            for waiter in group_map.remove(name).unwrap().waiters {
+                waiter.future.set_complete(());
+            }
+        }
+    }
+
+    // A dropped `wait` leaves the group's waiters.
+    fn cancel_wait(&self, name: &K, waiter_id: u64) {
+        if let Some(group) = self.state.group_map.lock_recover().get_mut(name) {
+            group.waiters.retain(|waiter| waiter.waiter_id != waiter_id);
+        }
+    }
+}
+
+pub struct WaitGroupGuard<K: Hash + Eq + Clone + Debug = String> {
+    group: NamedWaitGroup<K>,
+    name: K,
+}
+
+impl <K: Hash + Eq + Clone + Debug> Debug for WaitGroupGuard<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WaitGroupGuard").field("name", &self.name).finish()
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug> Drop for WaitGroupGuard<K> {
+    fn drop(&mut self) {
+        self.group.done(&self.name);
+    }
+}
+
+struct PendingGroup<'a, K: Hash + Eq + Clone + Debug> {
+    group: &'a NamedWaitGroup<K>,
+    name: K,
+    waiter_id: u64,
+    future: NotifyFuture<()>,
+    released: bool,
+}
+
+impl <'a, K: Hash + Eq + Clone + Debug> PendingGroup<'a, K> {
+    async fn wait(mut self) {
+        self.future.clone().await;
+        self.released = true;
+    }
+}
+
+impl <'a, K: Hash + Eq + Clone + Debug> Drop for PendingGroup<'a, K> {
+    fn drop(&mut self) {
+        if !self.released {
+            self.group.cancel_wait(&self.name, self.waiter_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use crate::NamedWaitGroup;
+    use crate::sync::{assert_send, assert_send_sync, LockRecover};
+
+    #[test]
+    fn test_named_wait_group() {
+        async_std::task::block_on(async {
+            let group: NamedWaitGroup = NamedWaitGroup::new();
+            let finished = Arc::new(AtomicUsize::new(0));
+            for i in 0..4 {
+                let guard = group.add("test_named_wait_group");
+                let finished = finished.clone();
+                async_std::task::spawn(async move {
+                    async_std::task::sleep(Duration::from_millis(50 + 20 * i)).await;
+                    finished.fetch_add(1, Ordering::SeqCst);
+                    drop(guard);
+                });
+            }
+            assert_eq!(group.count(&"test_named_wait_group".to_string()), 4);
+            // other names don't wait for the workers
+            let _other = group.add("test_named_wait_group_other");
+            group.wait("test_named_wait_group_idle").await;
+            group.wait("test_named_wait_group").await;
+            assert_eq!(finished.load(Ordering::SeqCst), 4);
+            assert_eq!(group.count(&"test_named_wait_group".to_string()), 0);
+            assert_eq!(format!("{:?}", group), "NamedWaitGroup { groups: 1 }");
+
+            // a cancelled wait leaves the group
+            let cancelled = async_std::future::timeout(Duration::from_millis(50), group.wait("test_named_wait_group_other")).await;
+            assert!(cancelled.is_err());
+            assert!(group.state.group_map.lock_recover()["test_named_wait_group_other"].waiters.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_auto_traits() {
+        assert_send_sync::<NamedWaitGroup>();
+        assert_send_sync::<crate::WaitGroupGuard>();
+        // the future of `wait` can be spawned on multi-threaded runtimes
+        let group: NamedWaitGroup = NamedWaitGroup::new();
+        assert_send(&group.wait("test_auto_traits"));
+    }
+}
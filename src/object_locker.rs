@@ -1,145 +1,3936 @@
-use std::sync::{Mutex};
-use std::collections::HashMap;
+use std::sync::{Arc, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::borrow::Cow;
+use std::fmt::Debug;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
+use std::future::Future;
 use std::ops::{Deref, DerefMut};
+use std::panic::AssertUnwindSafe;
+use std::pin::{pin, Pin};
+use std::sync::{LockResult, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use notify_future::NotifyFuture;
+use crate::sync::{LockRecover, Mutex};
+use crate::timer;
 
-struct LockerState {
-    pub is_locked: bool,
-    pub pending_list: Vec<NotifyFuture<()>>
+// Debug output goes through `log` unless the tracing feature is on. With it every guard (`Locker`,
+// `ReadLocker`, `SemaphorePermit`, ...) also keeps a `locker.held` span in its `_span` field, which closes
+// when the guard drops, so the span's lifetime is the time the locker was held.
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        #[cfg(not(feature = "tracing"))]
+        log::debug!($($arg)*);
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    }
 }
 
-struct LockerManager {
-    locker_map: Mutex<HashMap<String, LockerState>>
+// Identifies who holds a reentrant locker. Async tasks have no stable identity, so callers create one
+// with `LockOwner::new()` and pass it down the call chain that may re-enter the same locker.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct LockOwner(u64);
+
+impl LockOwner {
+    pub fn new() -> Self {
+        static OWNER_SEQ: AtomicU64 = AtomicU64::new(1);
+        Self(OWNER_SEQ.fetch_add(1, Ordering::Relaxed))
+    }
 }
 
-lazy_static::lazy_static! {
-    static ref LOCK_MANAGER: LockerManager = LockerManager::new();
+impl Default for LockOwner {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl LockerManager {
-    pub fn new() -> LockerManager {
+// Queued writers of a higher priority get the locker before lower ones, waiters of the same priority
+// keep their order.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum LockKind {
+    Write,
+    Read,
+    Permit,
+    // a read that may later turn into a write, and that turning
+    Upgradeable,
+    Upgrade,
+}
+
+#[derive(Clone, Copy)]
+struct LockRequest {
+    kind: LockKind,
+    owner: Option<LockOwner>,
+    reentrant: bool,
+    // fail instead of queueing past the manager's limits
+    checked: bool,
+    permit_limit: usize,
+    priority: Priority,
+}
+
+impl LockRequest {
+    fn write() -> Self {
         Self {
-            locker_map: Mutex::new(HashMap::new())
+            kind: LockKind::Write,
+            owner: None,
+            reentrant: false,
+            checked: false,
+            permit_limit: 0,
+            priority: Priority::Normal,
         }
     }
 
-    pub async fn lock(&self, locker_id: String) {
-        let future = {
-            let mut locker_map = self.locker_map.lock().unwrap();
-            let locker_info = locker_map.get_mut(&locker_id);
-            if locker_info.is_none() {
-                locker_map.insert(locker_id.clone(), LockerState {
-                    is_locked: true,
-                    pending_list: Vec::new()
-                });
-                log::debug!("LockerManager:get locker {}", locker_id);
+    fn permit(permit_limit: usize) -> Self {
+        assert!(permit_limit > 0, "a semaphore needs at least one permit");
+        Self {
+            kind: LockKind::Permit,
+            permit_limit,
+            ..Self::write()
+        }
+    }
+
+    fn read() -> Self {
+        Self {
+            kind: LockKind::Read,
+            ..Self::write()
+        }
+    }
+
+    fn upgradeable() -> Self {
+        Self {
+            kind: LockKind::Upgradeable,
+            ..Self::write()
+        }
+    }
+
+    fn upgrade() -> Self {
+        Self {
+            kind: LockKind::Upgrade,
+            ..Self::write()
+        }
+    }
+
+    fn owner(mut self, owner: LockOwner) -> Self {
+        self.owner = Some(owner);
+        self
+    }
+
+    fn reentrant(mut self) -> Self {
+        self.reentrant = true;
+        self
+    }
+
+    fn checked(mut self) -> Self {
+        self.checked = true;
+        self
+    }
+
+    fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockerError {
+    // the manager's `max_waiters` are already queued for the locker
+    WouldQueueTooDeep,
+    // `begin_shutdown` was called on the manager
+    ShuttingDown,
+    // the owner asking for the locker already holds it, without reentrancy it would wait for itself
+    WouldDeadlock,
+    // `lock_many` was given the same id twice, its second locker would wait for the first
+    DuplicateId,
+}
+
+impl std::fmt::Display for LockerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockerError::WouldQueueTooDeep => write!(f, "too many waiters queued for the locker"),
+            LockerError::ShuttingDown => write!(f, "the locker manager is shutting down"),
+            LockerError::WouldDeadlock => write!(f, "the owner already holds the locker"),
+            LockerError::DuplicateId => write!(f, "the same locker id was requested more than once"),
+        }
+    }
+}
+
+impl std::error::Error for LockerError {}
+
+// Threads blocked in `lock_blocking` wait on a condvar instead of a future.
+#[derive(Default)]
+struct BlockingSignal {
+    done: std::sync::Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl BlockingSignal {
+    fn wait(&self) {
+        let mut done = self.done.lock().unwrap_or_else(PoisonError::into_inner);
+        while !*done {
+            done = self.condvar.wait(done).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+enum WaiterSignal {
+    Future(NotifyFuture<()>),
+    Blocking(Arc<BlockingSignal>),
+}
+
+impl WaiterSignal {
+    fn complete(self) {
+        match self {
+            WaiterSignal::Future(future) => future.set_complete(()),
+            WaiterSignal::Blocking(signal) => {
+                *signal.done.lock().unwrap_or_else(PoisonError::into_inner) = true;
+                signal.condvar.notify_one();
+            }
+        }
+    }
+}
+
+struct LockerWaiter {
+    waiter_id: u64,
+    owner: Option<LockOwner>,
+    priority: Priority,
+    // how many higher priority writers were queued ahead of this one
+    overtaken: usize,
+    signal: WaiterSignal,
+}
+
+// `is_locked` is the exclusive (write) side, `readers` counts the shared holders and `permits` the
+// holders of a semaphore id, of which at most `permit_limit` run at once. The one upgradeable reader is
+// counted in `readers` too.
+struct LockerState {
+    pub is_locked: bool,
+    pub owner: Option<LockOwner>,
+    pub lock_count: usize,
+    pub readers: usize,
+    pub upgradeable: bool,
+    pub permits: usize,
+    pub permit_limit: usize,
+    // Waiters get the locker strictly in the order they started waiting, so none of them can starve.
+    // Writers are the exception, they are ordered by priority first.
+    pub pending_list: VecDeque<LockerWaiter>,
+    pub pending_readers: VecDeque<LockerWaiter>,
+    pub pending_permits: VecDeque<LockerWaiter>,
+    pub pending_upgradeable: VecDeque<LockerWaiter>,
+    // the upgradeable reader waiting for the other readers to leave, there is at most one
+    pub pending_upgrade: VecDeque<LockerWaiter>,
+    // set when a holder panicked, kept until `clear_poison` even while nobody uses the id
+    pub poisoned: bool,
+    // changes every time the write side is taken, so a TTL locker can tell whether it still holds it
+    pub epoch: u64,
+    // when the write side was taken, for finding stuck holders
+    pub locked_at: Option<Instant>,
+    // where the write side was taken, only captured if RUST_BACKTRACE asks for backtraces
+    #[cfg(feature = "hold-warnings")]
+    pub backtrace: Option<std::backtrace::Backtrace>,
+}
+
+impl LockerState {
+    fn new() -> Self {
+        Self {
+            is_locked: false,
+            owner: None,
+            lock_count: 0,
+            readers: 0,
+            upgradeable: false,
+            permits: 0,
+            permit_limit: 0,
+            pending_list: VecDeque::new(),
+            pending_readers: VecDeque::new(),
+            pending_permits: VecDeque::new(),
+            pending_upgradeable: VecDeque::new(),
+            pending_upgrade: VecDeque::new(),
+            poisoned: false,
+            epoch: 0,
+            locked_at: None,
+            #[cfg(feature = "hold-warnings")]
+            backtrace: None,
+        }
+    }
+
+    fn is_held(&self) -> bool {
+        self.is_locked || self.readers > 0 || self.permits > 0
+    }
+
+    fn pending_len(&self) -> usize {
+        self.pending_list.len() + self.pending_readers.len() + self.pending_permits.len()
+            + self.pending_upgradeable.len() + self.pending_upgrade.len()
+    }
+
+    fn is_free(&self) -> bool {
+        !self.is_held() && self.pending_len() == 0 && !self.poisoned
+    }
+
+    fn can_lock(&self, kind: LockKind) -> bool {
+        match kind {
+            LockKind::Write => !self.is_held(),
+            // a queued writer keeps new readers out, otherwise a steady stream of readers starves it, and so
+            // does a pending upgrade
+            LockKind::Read => {
+                !self.is_locked && self.permits == 0 && self.pending_list.is_empty() && self.pending_upgrade.is_empty()
+            }
+            LockKind::Upgradeable => {
+                self.can_lock(LockKind::Read) && !self.upgradeable && self.pending_upgradeable.is_empty()
+            }
+            // only the upgradeable reader itself is left
+            LockKind::Upgrade => self.upgradeable && self.readers == 1,
+            LockKind::Permit => {
+                !self.is_locked && self.readers == 0 && self.permits < self.permit_limit && self.pending_permits.is_empty()
+            }
+        }
+    }
+
+    fn take(&mut self, kind: LockKind, owner: Option<LockOwner>) {
+        match kind {
+            LockKind::Write => {
+                static EPOCH_SEQ: AtomicU64 = AtomicU64::new(1);
+                self.is_locked = true;
+                self.owner = owner;
+                self.lock_count = 1;
+                self.epoch = EPOCH_SEQ.fetch_add(1, Ordering::Relaxed);
+                self.locked_at = Some(Instant::now());
+                #[cfg(feature = "hold-warnings")]
+                {
+                    self.backtrace = Some(std::backtrace::Backtrace::capture());
+                }
+            }
+            LockKind::Read => {
+                self.readers += 1;
+            }
+            LockKind::Permit => {
+                self.permits += 1;
+            }
+            LockKind::Upgradeable => {
+                self.readers += 1;
+                self.upgradeable = true;
+            }
+            LockKind::Upgrade => {
+                self.readers -= 1;
+                self.upgradeable = false;
+                self.take(LockKind::Write, owner);
+            }
+        }
+    }
+
+    // Queues a writer behind every writer of at least its priority. With `max_overtakes` set, a waiter that
+    // was already passed that many times isn't passed anymore, so a steady stream of high priority writers
+    // can't starve the low priority ones.
+    fn push_writer(&mut self, waiter: LockerWaiter, max_overtakes: Option<usize>) {
+        let mut pos = self.pending_list.len();
+        while pos > 0 {
+            let ahead = &self.pending_list[pos - 1];
+            if ahead.priority >= waiter.priority || max_overtakes.is_some_and(|max| ahead.overtaken >= max) {
+                break;
+            }
+            pos -= 1;
+        }
+        for overtaken in self.pending_list.range_mut(pos..) {
+            overtaken.overtaken += 1;
+        }
+        self.pending_list.insert(pos, waiter);
+    }
+
+    fn release_upgradeable(&mut self) {
+        self.readers -= 1;
+        self.upgradeable = false;
+        self.wake_waiters(false);
+    }
+
+    fn release_write(&mut self, phase_fair: bool) {
+        self.is_locked = false;
+        self.owner = None;
+        self.lock_count = 0;
+        self.locked_at = None;
+        #[cfg(feature = "hold-warnings")]
+        {
+            self.backtrace = None;
+        }
+        self.wake_waiters(phase_fair);
+    }
+
+    // Hands the locker to as many waiters as can hold it now, and wakes only those, so no release makes
+    // waiters resume just to queue again. A released write or upgrade wakes one writer, a released permit
+    // one permit waiter per free permit. The pending readers all wake together, but only once no writer is
+    // queued, after the last reader leaves the next writer goes. With `readers_first` (the manager's
+    // `phase_fair`) the readers queued behind a leaving writer go first even if another writer is queued,
+    // so a steady stream of writers can't starve them.
+    fn wake_waiters(&mut self, readers_first: bool) {
+        if self.is_locked {
+            return;
+        }
+        if self.can_lock(LockKind::Upgrade) {
+            if let Some(waiter) = self.pending_upgrade.pop_front() {
+                self.take(LockKind::Upgrade, None);
+                waiter.signal.complete();
                 return;
-            } else {
-                let state = locker_info.unwrap();
-                if state.is_locked {
-                    let future = NotifyFuture::new();
-                    state.pending_list.push(future.clone());
-                    future
-                } else {
-                    state.is_locked = true;
-                    log::debug!("LockerManager:get locker {}", locker_id);
+            }
+        }
+        if self.permits == 0 {
+            let readers_waiting = !self.pending_readers.is_empty() || !self.pending_upgradeable.is_empty();
+            if self.readers == 0 && (!readers_first || !readers_waiting) {
+                if let Some(waiter) = self.pending_list.pop_front() {
+                    self.take(LockKind::Write, waiter.owner);
+                    waiter.signal.complete();
                     return;
                 }
             }
-        };
-        log::debug!("LockerManager:waiting locker {}", locker_id);
-        future.await;
-        log::debug!("LockerManager:get locker {}", locker_id);
-    }
-
-    pub fn unlock(&self, locker_id: &str) {
-        let mut locker_map = self.locker_map.lock().unwrap();
-        let locker_info = locker_map.get_mut(locker_id);
-        if locker_info.is_some() {
-            let state = locker_info.unwrap();
-            if state.pending_list.len() > 0 {
-                let future = state.pending_list.remove(0);
-                future.set_complete(());
-            } else {
-                state.is_locked = false;
+            // readers that arrive during an upgrade wait for it, as `can_lock` makes them
+            if (readers_first || self.pending_list.is_empty()) && self.pending_upgrade.is_empty() {
+                while let Some(waiter) = self.pending_readers.pop_front() {
+                    self.take(LockKind::Read, None);
+                    waiter.signal.complete();
+                }
+                if !self.upgradeable {
+                    if let Some(waiter) = self.pending_upgradeable.pop_front() {
+                        self.take(LockKind::Upgradeable, None);
+                        waiter.signal.complete();
+                    }
+                }
+            }
+        }
+        if self.readers == 0 {
+            while self.permits < self.permit_limit {
+                match self.pending_permits.pop_front() {
+                    Some(waiter) => {
+                        self.take(LockKind::Permit, None);
+                        waiter.signal.complete();
+                    }
+                    None => break,
+                }
             }
-        } else {
-            assert!(false);
         }
-        log::debug!("LockerManager:free locker {}", locker_id);
     }
 }
 
-pub struct Locker {
-    locker_id: String,
+// A locker id together with its hash, computed once by the manager's hasher. The map only passes the
+// stored hash through, so lockers and key handles that keep their id hashed never hash it again.
+#[derive(Clone)]
+struct HashedKey<K> {
+    hash: u64,
+    key: K,
 }
 
-impl Locker {
-    pub async fn get_locker(locker_id: impl Into<String>) -> Self {
-        let id = locker_id.into();
-        LOCK_MANAGER.lock(id.clone()).await;
-        Self {
-            locker_id: id
-        }
+impl <K: Eq> PartialEq for HashedKey<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.key == other.key
     }
 }
 
-impl Drop for Locker {
-    fn drop(&mut self) {
-        LOCK_MANAGER.unlock(self.locker_id.as_str());
+impl <K: Eq> Eq for HashedKey<K> {}
+
+impl <K> Hash for HashedKey<K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
     }
 }
 
-pub struct GuardObject<T> {
-    _locker: Locker,
-    obj: T
+impl <K: Debug> Debug for HashedKey<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.key.fmt(f)
+    }
 }
 
-impl <T> GuardObject<T> {
-    pub fn new(locker: Locker, obj: T) -> Self {
+#[derive(Default)]
+struct PrehashedHasher(u64);
+
+impl Hasher for PrehashedHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        // only `write_u64` is used by `HashedKey`, this just keeps the hasher total
+        for byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ *byte as u64;
+        }
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+type LockerMap<K> = HashMap<HashedKey<K>, LockerState, BuildHasherDefault<PrehashedHasher>>;
+
+// Instrumentation hooks that work without the metrics or tracing features. They are called after the
+// manager's lock is released, so an observer may call back into the manager. Every acquisition is
+// matched by one release, also for each level of a reentrant locker.
+pub trait LockerObserver<K>: Send + Sync {
+    fn on_acquire(&self, _locker_id: &K, _waited: Duration) {}
+
+    fn on_release(&self, _locker_id: &K) {}
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LockerMetrics {
+    pub acquisitions: u64,
+    pub immediate_acquisitions: u64,
+    pub contended_acquisitions: u64,
+    pub max_pending_depth: usize,
+}
+
+struct LockerManagerState<K, S> {
+    locker_map: Mutex<LockerMap<K>>,
+    hash_builder: S,
+    waiter_seq: AtomicU64,
+    #[cfg(debug_assertions)]
+    invalid_unlock_count: AtomicU64,
+    #[cfg(feature = "metrics")]
+    metrics: Mutex<LockerMetrics>,
+    max_waiters: Option<usize>,
+    max_overtakes: Option<usize>,
+    phase_fair: bool,
+    panic_after_shutdown: bool,
+    #[cfg(feature = "hold-warnings")]
+    warn_after: Option<Duration>,
+    shutting_down: AtomicBool,
+    // `await_drained` callers, completed once nothing is held or queued anymore
+    drain_waiters: Mutex<Vec<NotifyFuture<()>>>,
+    observer: Mutex<Option<Arc<dyn LockerObserver<K>>>>,
+}
+
+// Lockers are only exclusive within the manager that handed them out, the global manager behind
+// `Locker::get_locker` is just the default one. Clones share the same lockers.
+// Any hashable key may name a locker, `LockerManager` and `Locker` without parameters are keyed by `String`.
+// Ids are hashed with `S`, the default `RandomState` is randomly seeded. A faster or deterministic hasher
+// is fine for ids the program chooses, but if callers can pick ids they can then pick colliding ones and
+// make every lookup slow.
+pub struct LockerManager<K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    state: Arc<LockerManagerState<K, S>>,
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Clone for LockerManager<K, S> {
+    fn clone(&self) -> Self {
         Self {
-            _locker: locker,
-            obj
+            state: self.state.clone()
         }
     }
+}
 
-    pub fn release_locker(self) -> T {
-        self.obj
+lazy_static::lazy_static! {
+    static ref LOCK_MANAGER: LockerManager = LockerManager::new();
+}
+
+impl <K: Hash + Eq + Clone + Debug + Ord, S: BuildHasher> LockerManager<K, S> {
+    // Takes every locker in sorted order so that callers asking for overlapping sets in different orders
+    // can't deadlock each other. The lockers are returned in reverse acquisition order, dropping the Vec
+    // releases the last acquired one first. Duplicate ids are only locked once instead of waiting for
+    // themselves.
+    pub async fn get_lockers(&self, mut locker_ids: Vec<K>) -> Vec<Locker<K, S>> {
+        locker_ids.sort();
+        locker_ids.dedup();
+        let mut lockers = Vec::with_capacity(locker_ids.len());
+        for locker_id in locker_ids {
+            lockers.push(self.get_locker(locker_id).await);
+        }
+        lockers.reverse();
+        lockers
     }
 }
 
-impl <T> Deref for GuardObject<T> {
-    type Target = T;
+impl <K: Hash + Eq + Clone + Debug + Ord, S: BuildHasher> LockerManager<K, S> {
+    // Like `get_lockers`, each object guarded by the locker of its id. Unlike ids, objects can't be
+    // merged, so a duplicate id fails with `LockerError::DuplicateId` before anything is locked.
+    pub async fn lock_many<T>(&self, mut objs: Vec<(K, T)>) -> Result<LockSet<T, K, S>, LockerError> {
+        objs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        if objs.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            debug_log!("LockerManager:duplicate id passed to lock_many");
+            return Err(LockerError::DuplicateId);
+        }
+        let mut set = LockSet::new();
+        for (locker_id, obj) in objs {
+            set.push(GuardObject::new(self.get_locker(locker_id).await, obj));
+        }
+        Ok(set)
+    }
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.obj
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Debug for LockerManager<K, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LockerManager")
+            .field("tracked", &self.state.locker_map.lock_recover().len())
+            .finish()
     }
 }
 
-impl <T> DerefMut for GuardObject<T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.obj
+pub struct LockerManagerBuilder<K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    max_waiters: Option<usize>,
+    max_overtakes: Option<usize>,
+    phase_fair: bool,
+    panic_after_shutdown: bool,
+    #[cfg(feature = "hold-warnings")]
+    warn_after: Option<Duration>,
+    hash_builder: S,
+    _key: std::marker::PhantomData<K>,
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> LockerManagerBuilder<K, S> {
+    // Once this many callers wait for the same locker, `get_locker_checked` fails with
+    // `LockerError::WouldQueueTooDeep` instead of queueing another one. Plain `get_locker` always queues.
+    pub fn max_waiters(mut self, max_waiters: usize) -> Self {
+        self.max_waiters = Some(max_waiters);
+        self
+    }
+
+    // Ages waiters of `get_locker_prio`: once higher priority writers were queued ahead of a waiter this
+    // many times, later ones queue behind it. Without it priority is strict.
+    pub fn max_overtakes(mut self, max_overtakes: usize) -> Self {
+        self.max_overtakes = Some(max_overtakes);
+        self
+    }
+
+    // Alternates writers with the readers that queued behind them: a released write lets the waiting
+    // readers go before the next writer. Without it queued writers go first, and readers only get the
+    // locker once no writer waits.
+    pub fn phase_fair(mut self) -> Self {
+        self.phase_fair = true;
+        self
+    }
+
+    // Makes the plain acquisitions (`get_locker`, `lock`, `get_read_locker`, ...) panic once `begin_shutdown`
+    // was called, instead of still handing out lockers, for programs where taking a locker after that
+    // is a bug. Upgrades of upgradeable lockers already held still go ahead.
+    pub fn panic_after_shutdown(mut self) -> Self {
+        self.panic_after_shutdown = true;
+        self
+    }
+
+    // With the hold-warnings feature, releasing a write locker that was held for longer than `warn_after`
+    // logs a warning with its id, and with where it was taken if backtraces are enabled.
+    #[cfg(feature = "hold-warnings")]
+    pub fn warn_after(mut self, warn_after: Duration) -> Self {
+        self.warn_after = Some(warn_after);
+        self
+    }
+
+    pub fn hasher(mut self, hash_builder: S) -> Self {
+        self.hash_builder = hash_builder;
+        self
+    }
+
+    pub fn build(self) -> LockerManager<K, S> {
+        LockerManager {
+            state: Arc::new(LockerManagerState {
+                locker_map: Mutex::new(LockerMap::default()),
+                hash_builder: self.hash_builder,
+                waiter_seq: AtomicU64::new(0),
+                #[cfg(debug_assertions)]
+                invalid_unlock_count: AtomicU64::new(0),
+                #[cfg(feature = "metrics")]
+                metrics: Mutex::new(LockerMetrics::default()),
+                max_waiters: self.max_waiters,
+                max_overtakes: self.max_overtakes,
+                phase_fair: self.phase_fair,
+                panic_after_shutdown: self.panic_after_shutdown,
+                #[cfg(feature = "hold-warnings")]
+                warn_after: self.warn_after,
+                shutting_down: AtomicBool::new(false),
+                drain_waiters: Mutex::new(Vec::new()),
+                observer: Mutex::new(None),
+            })
+        }
     }
 }
 
-#[cfg(test)]
-mod test {
-    use std::sync::{Arc, Mutex};
-    use std::time::Duration;
-    use crate::Locker;
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher + Default> Default for LockerManager<K, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    #[test]
-    fn test() {
-        async_std::task::block_on(async {
-            let _locker = Locker::get_locker("test".to_string()).await;
-            let i = Arc::new(Mutex::new(0));
-            let i_copy = i.clone();
-            async_std::task::spawn(async move {
-                let _locker = Locker::get_locker("test").await;
-                assert_eq!(*i_copy.lock().unwrap(), 1);
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher + Default> LockerManager<K, S> {
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> LockerManagerBuilder<K, S> {
+        LockerManagerBuilder {
+            max_waiters: None,
+            max_overtakes: None,
+            phase_fair: false,
+            panic_after_shutdown: false,
+            #[cfg(feature = "hold-warnings")]
+            warn_after: None,
+            hash_builder: S::default(),
+            _key: std::marker::PhantomData,
+        }
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> LockerManager<K, S> {
+
+    fn hashed(&self, locker_id: K) -> HashedKey<K> {
+        HashedKey {
+            hash: self.state.hash_builder.hash_one(&locker_id),
+            key: locker_id,
+        }
+    }
+
+    // Hashes the id once for repeated operations on it, see `KeyHandle`.
+    pub fn key(&self, locker_id: impl Into<K>) -> KeyHandle<'_, K, S> {
+        KeyHandle {
+            manager: self,
+            locker_id: self.hashed(locker_id.into()),
+        }
+    }
+
+    pub async fn get_locker(&self, locker_id: impl Into<K>) -> Locker<K, S> {
+        let id = self.hashed(locker_id.into());
+        let contended = self.acquire_unchecked(&id, LockRequest::write()).await;
+        Locker::new(self.clone(), id).with_contended(contended)
+    }
+
+    // Waits ahead of queued lockers of a lower priority, see `Priority`. `get_locker` is `Priority::Normal`.
+    pub async fn get_locker_prio(&self, locker_id: impl Into<K>, priority: Priority) -> Locker<K, S> {
+        let id = self.hashed(locker_id.into());
+        let contended = self.acquire_unchecked(&id, LockRequest::write().priority(priority)).await;
+        Locker::new(self.clone(), id).with_contended(contended)
+    }
+
+    // Also returns how long the caller waited, near zero when the locker was free.
+    pub async fn get_locker_timed(&self, locker_id: impl Into<K>) -> (Locker<K, S>, Duration) {
+        let start = Instant::now();
+        let locker = self.get_locker(locker_id).await;
+        (locker, start.elapsed())
+    }
+
+    // The locker is released after `ttl` even if it is still held, e.g. because its holder got stuck,
+    // and the next waiter proceeds. Dropping the locker after that does nothing. With the `tokio` feature the
+    // expiry task is spawned on the current tokio runtime, so this must be called inside one.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn get_locker_with_ttl(&self, locker_id: impl Into<K>, ttl: Duration) -> Locker<K, S>
+        where K: Send + 'static, S: Send + Sync + 'static {
+        let mut locker = self.get_locker(locker_id).await;
+        let epoch = self.state.locker_map.lock_recover()[&locker.locker_id].epoch;
+        locker.epoch = Some(epoch);
+        let manager = self.clone();
+        let id = locker.locker_id.clone();
+        timer::spawn(async move {
+            timer::sleep(ttl).await;
+            debug_log!("LockerManager:locker {:?} ttl expired", id);
+            manager.unlock_epoch(&id, epoch);
+        });
+        locker
+    }
+
+    // Keeps the id claimed for `hold_after_release` after the locker is dropped, e.g. to catch retries of a
+    // request by its idempotency key. Callers asking for the id in that window wait until it is over, then
+    // the id is released and forgotten like any other. Under `tokio` the window is timed by the current
+    // tokio runtime as well.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn claim(&self, locker_id: impl Into<K>, hold_after_release: Duration) -> Locker<K, S>
+        where K: Send + Sync + 'static, S: Send + Sync + 'static {
+        let mut locker = self.get_locker(locker_id).await;
+        let manager = self.clone();
+        let id = locker.locker_id.clone();
+        locker.delayed_release = Some(Box::new(move || {
+            timer::spawn(async move {
+                timer::sleep(hold_after_release).await;
+                debug_log!("LockerManager:claim of locker {:?} ended", id);
+                manager.unlock_hashed(&id, true);
             });
-            async_std::task::sleep(Duration::from_secs(5)).await;
-            *i.lock().unwrap() = 1;
+        }));
+        locker
+    }
+
+    // Holds the locker exactly while the future returned by `f` runs, so the critical section can't
+    // outlive the lock or lose it early.
+    pub async fn with_locker<F, Fut, R>(&self, locker_id: impl Into<K>, f: F) -> R
+        where F: FnOnce() -> Fut, Fut: Future<Output = R> {
+        let _locker = self.get_locker(locker_id).await;
+        f().await
+    }
+
+    // Like `std::sync::Mutex::lock`, the locker is handed out either way, an error only tells that a
+    // previous holder panicked while holding it and the protected resource may be inconsistent.
+    pub async fn get_locker_poisonable(&self, locker_id: impl Into<K>) -> LockResult<Locker<K, S>> {
+        let locker = self.get_locker(locker_id).await;
+        if self.state.locker_map.lock_recover()[&locker.locker_id].poisoned {
+            Err(PoisonError::new(locker))
+        } else {
+            Ok(locker)
+        }
+    }
+
+    pub async fn get_locker_checked(&self, locker_id: impl Into<K>) -> Result<Locker<K, S>, LockerError> {
+        let id = self.hashed(locker_id.into());
+        let contended = self.acquire(&id, LockRequest::write().checked()).await?;
+        Ok(Locker::new(self.clone(), id).with_contended(contended))
+    }
+
+    // Queues for the write side like `get_locker`, and also returns a ticket that tells the request's
+    // place in the queue and can take it out of the queue without dropping the future.
+    pub fn lock_handle(&self, locker_id: impl Into<K>) -> (AcquireFuture<K, S>, WaitTicket<K, S>) {
+        self.lock_handle_request(locker_id.into(), LockRequest::write()).expect("unchecked lock requests never fail")
+    }
+
+    pub fn lock_handle_checked(&self, locker_id: impl Into<K>) -> Result<LockHandle<K, S>, LockerError> {
+        self.lock_handle_request(locker_id.into(), LockRequest::write().checked())
+    }
+
+    fn lock_handle_request(&self, locker_id: K, request: LockRequest) -> Result<LockHandle<K, S>, LockerError> {
+        let id = self.hashed(locker_id);
+        let start = Instant::now();
+        let waiter = self.lock_or_wait(&id, request)?.map(|mut pending| {
+            // the acquire future takes over the wait, and the cleanup if it is dropped
+            pending.acquired = true;
+            (pending.waiter_id, pending.future.clone())
+        });
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let ticket = WaitTicket {
+            manager: self.clone(),
+            locker_id: id.clone(),
+            waiter_id: waiter.as_ref().map(|(waiter_id, _)| *waiter_id),
+            cancelled: cancelled.clone(),
+        };
+        let future = AcquireFuture {
+            manager: self.clone(),
+            locker_id: id,
+            waiter,
+            cancelled,
+            start,
+            done: false,
+        };
+        Ok((future, ticket))
+    }
+
+    // `get_locker` as a named future that can be stored or polled by hand. Unlike the async fn it queues
+    // right away, not when first polled.
+    pub fn lock_acquire(&self, locker_id: impl Into<K>) -> LockAcquire<K, S> {
+        let (future, _) = self.lock_handle(locker_id);
+        LockAcquire {
+            future
+        }
+    }
+
+    // Takes the locker if it is free and queues for it otherwise, both under one lock of the map, so a
+    // release in between can't be missed the way it can between `try_get_locker` and `get_locker`.
+    pub fn try_or_wait(&self, locker_id: impl Into<K>) -> TryOrWait<K, S> {
+        let mut acquire = self.lock_acquire(locker_id);
+        if acquire.future.waiter.is_some() {
+            return TryOrWait::Wait(acquire);
+        }
+        TryOrWait::Locked(acquire.future.complete().expect("a request that didn't queue can't be cancelled"))
+    }
+
+    pub fn try_get_locker(&self, locker_id: impl Into<K>) -> Option<Locker<K, S>> {
+        let id = self.hashed(locker_id.into());
+        if self.try_lock_hashed(&id) {
+            Some(Locker::new(self.clone(), id))
+        } else {
+            None
+        }
+    }
+
+    // Tries up to `max_attempts` times without queueing, sleeping `backoff` after the first failed try and
+    // twice as long after each further one. For callers that would rather give up than wait in line.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn get_locker_spin(&self, locker_id: impl Into<K>, max_attempts: usize, backoff: Duration) -> Option<Locker<K, S>> {
+        let id = self.hashed(locker_id.into());
+        let mut backoff = backoff;
+        for attempt in 0..max_attempts {
+            if attempt > 0 {
+                timer::sleep(backoff).await;
+                backoff *= 2;
+            }
+            if self.try_lock_hashed(&id) {
+                return Some(Locker::new(self.clone(), id).with_contended(attempt > 0));
+            }
+        }
+        debug_log!("LockerManager:gave up on locker {:?} after {} attempts", id, max_attempts);
+        None
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn get_locker_timeout(&self, locker_id: impl Into<K>, timeout: Duration) -> Option<Locker<K, S>> {
+        self.get_locker_timeout_with(locker_id, timer::sleep(timeout)).await
+    }
+
+    // Runs `fallback` instead when the locker can't be got within `timeout`, for callers that degrade
+    // gracefully. The timed out request has left the queue by the time `fallback` runs.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn get_or_else<R>(&self, locker_id: impl Into<K>, timeout: Duration, fallback: impl FnOnce() -> R) -> Result<Locker<K, S>, R> {
+        self.get_locker_timeout(locker_id, timeout).await.ok_or_else(fallback)
+    }
+
+    // Gives up once `sleep` completes, any runtime's sleep future will do.
+    pub async fn get_locker_timeout_with(&self, locker_id: impl Into<K>, sleep: impl Future<Output = ()>) -> Option<Locker<K, S>> {
+        let id = self.hashed(locker_id.into());
+        let contended = self.lock_timeout_hashed(&id, sleep).await?;
+        Some(Locker::new(self.clone(), id).with_contended(contended))
+    }
+
+    // A reentrant locker is only reentrant for the same owner, other owners and plain lockers still wait
+    // until every nested locker of the owner has been dropped.
+    pub async fn get_reentrant_locker(&self, locker_id: impl Into<K>, owner: LockOwner) -> Locker<K, S> {
+        let id = self.hashed(locker_id.into());
+        let contended = self.acquire_unchecked(&id, LockRequest::write().owner(owner).reentrant()).await;
+        Locker::new(self.clone(), id).with_contended(contended)
+    }
+
+    // Records `owner` as the holder so that, with the deadlock-detection feature, a wait that closes a cycle
+    // between owners is logged as an error. Unlike `get_reentrant_locker` the same owner locking twice blocks.
+    pub async fn get_locker_with_owner(&self, locker_id: impl Into<K>, owner: LockOwner) -> Locker<K, S> {
+        let id = self.hashed(locker_id.into());
+        let contended = self.acquire_unchecked(&id, LockRequest::write().owner(owner)).await;
+        Locker::new(self.clone(), id).with_contended(contended)
+    }
+
+    // Fails with `LockerError::WouldDeadlock` instead of waiting forever if `owner` already holds the
+    // locker, and like `get_locker_checked` otherwise.
+    pub async fn get_locker_with_owner_checked(&self, locker_id: impl Into<K>, owner: LockOwner) -> Result<Locker<K, S>, LockerError> {
+        let id = self.hashed(locker_id.into());
+        let contended = self.acquire(&id, LockRequest::write().owner(owner).checked()).await?;
+        Ok(Locker::new(self.clone(), id).with_contended(contended))
+    }
+
+    // Readers of an id run concurrently with each other and exclude writers. `get_locker` is the same
+    // as taking the write side.
+    pub async fn get_read_locker(&self, locker_id: impl Into<K>) -> ReadLocker<K, S> {
+        let id = self.hashed(locker_id.into());
+        self.acquire_unchecked(&id, LockRequest::read()).await;
+        ReadLocker::new(self.clone(), id)
+    }
+
+    pub async fn get_read_locker_checked(&self, locker_id: impl Into<K>) -> Result<ReadLocker<K, S>, LockerError> {
+        let id = self.hashed(locker_id.into());
+        self.acquire(&id, LockRequest::read().checked()).await?;
+        Ok(ReadLocker::new(self.clone(), id))
+    }
+
+    pub async fn get_write_locker(&self, locker_id: impl Into<K>) -> WriteLocker<K, S> {
+        let id = self.hashed(locker_id.into());
+        self.acquire_unchecked(&id, LockRequest::write()).await;
+        WriteLocker::new(self.clone(), id)
+    }
+
+    // Reads alongside plain readers, but only one upgradeable locker of an id exists at a time, so its
+    // holder can check under the read and then `upgrade` to a write without anyone writing in between.
+    pub async fn get_upgradeable_locker(&self, locker_id: impl Into<K>) -> UpgradeableLocker<K, S> {
+        let id = self.hashed(locker_id.into());
+        self.acquire_unchecked(&id, LockRequest::upgradeable()).await;
+        UpgradeableLocker::new(self.clone(), id)
+    }
+
+    pub async fn get_upgradeable_locker_checked(&self, locker_id: impl Into<K>) -> Result<UpgradeableLocker<K, S>, LockerError> {
+        let id = self.hashed(locker_id.into());
+        self.acquire(&id, LockRequest::upgradeable().checked()).await?;
+        Ok(UpgradeableLocker::new(self.clone(), id))
+    }
+
+    // Up to `permits_available` holders of a semaphore id run at once, the rest wait in FIFO order. All
+    // callers of an id are expected to pass the same limit, the latest one is what counts.
+    pub async fn get_semaphore(&self, locker_id: impl Into<K>, permits_available: usize) -> SemaphorePermit<K, S> {
+        let id = self.hashed(locker_id.into());
+        self.acquire_unchecked(&id, LockRequest::permit(permits_available)).await;
+        SemaphorePermit::new(self.clone(), id)
+    }
+
+    pub async fn get_semaphore_checked(&self, locker_id: impl Into<K>, permits_available: usize) -> Result<SemaphorePermit<K, S>, LockerError> {
+        let id = self.hashed(locker_id.into());
+        self.acquire(&id, LockRequest::permit(permits_available).checked()).await?;
+        Ok(SemaphorePermit::new(self.clone(), id))
+    }
+
+    pub async fn lock(&self, locker_id: K) {
+        self.acquire_unchecked(&self.hashed(locker_id), LockRequest::write()).await;
+    }
+
+    pub async fn lock_checked(&self, locker_id: K) -> Result<(), LockerError> {
+        self.acquire(&self.hashed(locker_id), LockRequest::write().checked()).await?;
+        Ok(())
+    }
+
+    pub async fn lock_read(&self, locker_id: K) {
+        self.acquire_unchecked(&self.hashed(locker_id), LockRequest::read()).await;
+    }
+
+    pub async fn lock_with_owner(&self, locker_id: K, owner: LockOwner) {
+        self.acquire_unchecked(&self.hashed(locker_id), LockRequest::write().owner(owner)).await;
+    }
+
+    pub async fn lock_reentrant(&self, locker_id: K, owner: LockOwner) {
+        self.acquire_unchecked(&self.hashed(locker_id), LockRequest::write().owner(owner).reentrant()).await;
+    }
+
+    // Returns false if the locker could not be got before the timeout.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn lock_timeout(&self, locker_id: K, timeout: Duration) -> bool {
+        self.lock_timeout_with(locker_id, timer::sleep(timeout)).await
+    }
+
+    pub async fn lock_timeout_with(&self, locker_id: K, sleep: impl Future<Output = ()>) -> bool {
+        self.lock_timeout_hashed(&self.hashed(locker_id), sleep).await.is_some()
+    }
+
+    // Tells whether the caller had to wait for the locker, or None if it timed out.
+    async fn lock_timeout_hashed(&self, locker_id: &HashedKey<K>, sleep: impl Future<Output = ()>) -> Option<bool> {
+        let contended = timer::timeout(self.acquire_unchecked(locker_id, LockRequest::write()), sleep).await;
+        if contended.is_none() {
+            debug_log!("LockerManager:timeout locker {:?}", locker_id);
+        }
+        contended
+    }
+
+    // With the tracing feature every acquisition runs in a `locker.acquire` span that records how long
+    // it waited.
+    // Returns whether the caller had to queue for the locker.
+    async fn acquire(&self, locker_id: &HashedKey<K>, request: LockRequest) -> Result<bool, LockerError> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("locker.acquire", id = ?locker_id, wait_us = tracing::field::Empty);
+        let start = Instant::now();
+        let waiter = self.lock_or_wait(locker_id, request)?;
+        let contended = waiter.is_some();
+        if let Some(waiter) = waiter {
+            debug_log!("LockerManager:waiting locker {:?}", locker_id);
+            waiter.wait().await;
+            debug_log!("LockerManager:get locker {:?}", locker_id);
+        }
+        #[cfg(feature = "tracing")]
+        {
+            span.record("wait_us", start.elapsed().as_micros() as u64);
+            tracing::debug!(parent: &span, id = ?locker_id, "locker acquired");
+        }
+        // an upgrade keeps holding what was acquired before
+        if request.kind != LockKind::Upgrade {
+            self.notify_acquire(locker_id, start.elapsed());
+        }
+        Ok(contended)
+    }
+
+    async fn acquire_unchecked(&self, locker_id: &HashedKey<K>, request: LockRequest) -> bool {
+        self.acquire(locker_id, request).await.expect("unchecked lock requests never fail")
+    }
+
+    pub fn try_lock(&self, locker_id: &K) -> bool {
+        self.try_lock_hashed(&self.hashed(locker_id.clone()))
+    }
+
+    // Unlike `try_lock`, tells a refusal because of `begin_shutdown` apart from the locker being taken.
+    pub fn try_lock_checked(&self, locker_id: &K) -> Result<bool, LockerError> {
+        if self.is_shutting_down() {
+            return Err(LockerError::ShuttingDown);
+        }
+        Ok(self.try_lock(locker_id))
+    }
+
+    // Blocks the current thread until the write side is taken, for sync code sharing lockers with async
+    // code. Blocked threads queue with the async waiters, so either kind gets the locker in the order it
+    // asked for it. Not for use inside async tasks, it blocks the executor thread.
+    pub fn lock_blocking(&self, locker_id: K) {
+        self.lock_blocking_hashed(&self.hashed(locker_id));
+    }
+
+    pub fn get_locker_blocking(&self, locker_id: impl Into<K>) -> Locker<K, S> {
+        let id = self.hashed(locker_id.into());
+        let contended = self.lock_blocking_hashed(&id);
+        Locker::new(self.clone(), id).with_contended(contended)
+    }
+
+    // Only refuses during shutdown, blocked threads don't count towards `max_waiters`.
+    pub fn get_locker_blocking_checked(&self, locker_id: impl Into<K>) -> Result<Locker<K, S>, LockerError> {
+        if self.is_shutting_down() {
+            return Err(LockerError::ShuttingDown);
+        }
+        Ok(self.get_locker_blocking(locker_id))
+    }
+
+    fn lock_blocking_hashed(&self, locker_id: &HashedKey<K>) -> bool {
+        if self.is_shutting_down() {
+            self.refuse_after_shutdown(locker_id);
+        }
+        let start = Instant::now();
+        let signal = {
+            let mut locker_map = self.state.locker_map.lock_recover();
+            let state = locker_map.entry(locker_id.clone()).or_insert_with(LockerState::new);
+            if state.can_lock(LockKind::Write) {
+                state.take(LockKind::Write, None);
+                #[cfg(feature = "metrics")]
+                self.record_immediate();
+                debug_log!("LockerManager:get locker {:?}", locker_id);
+                drop(locker_map);
+                self.notify_acquire(locker_id, Duration::ZERO);
+                return false;
+            }
+            let signal = Arc::new(BlockingSignal::default());
+            state.push_writer(LockerWaiter {
+                waiter_id: self.state.waiter_seq.fetch_add(1, Ordering::Relaxed),
+                owner: None,
+                priority: Priority::Normal,
+                overtaken: 0,
+                signal: WaiterSignal::Blocking(signal.clone()),
+            }, self.state.max_overtakes);
+            #[cfg(feature = "metrics")]
+            self.record_queued(state.pending_len());
+            signal
+        };
+        debug_log!("LockerManager:blocking on locker {:?}", locker_id);
+        signal.wait();
+        #[cfg(feature = "metrics")]
+        {
+            self.state.metrics.lock_recover().acquisitions += 1;
+        }
+        debug_log!("LockerManager:get locker {:?}", locker_id);
+        self.notify_acquire(locker_id, start.elapsed());
+        true
+    }
+
+    fn try_lock_hashed(&self, locker_id: &HashedKey<K>) -> bool {
+        if self.is_shutting_down() {
+            return false;
+        }
+        let mut locker_map = self.state.locker_map.lock_recover();
+        let state = locker_map.entry(locker_id.clone()).or_insert_with(LockerState::new);
+        if !state.can_lock(LockKind::Write) {
+            return false;
+        }
+        state.take(LockKind::Write, None);
+        #[cfg(feature = "metrics")]
+        self.record_immediate();
+        debug_log!("LockerManager:get locker {:?}", locker_id);
+        drop(locker_map);
+        self.notify_acquire(locker_id, Duration::ZERO);
+        true
+    }
+
+    fn lock_or_wait<'a>(&'a self, locker_id: &'a HashedKey<K>, request: LockRequest) -> Result<Option<PendingLocker<'a, K, S>>, LockerError> {
+        let LockRequest { kind, owner, reentrant, checked, permit_limit, priority } = request;
+        if self.is_shutting_down() {
+            if checked {
+                return Err(LockerError::ShuttingDown);
+            }
+            if kind != LockKind::Upgrade {
+                self.refuse_after_shutdown(locker_id);
+            }
+        }
+        let mut locker_map = self.state.locker_map.lock_recover();
+        let state = locker_map.entry(locker_id.clone()).or_insert_with(LockerState::new);
+        if kind == LockKind::Permit {
+            state.permit_limit = permit_limit;
+        }
+        if reentrant && state.is_locked && state.owner == owner {
+            state.lock_count += 1;
+            debug_log!("LockerManager:reenter locker {:?} count {}", locker_id, state.lock_count);
+            return Ok(None);
+        }
+        if state.can_lock(kind) {
+            state.take(kind, owner);
+            #[cfg(feature = "metrics")]
+            self.record_immediate();
+            debug_log!("LockerManager:get locker {:?}", locker_id);
+            return Ok(None);
+        }
+        if checked && owner.is_some() && state.is_locked && state.owner == owner {
+            debug_log!("LockerManager:owner {:?} already holds locker {:?}", owner, locker_id);
+            return Err(LockerError::WouldDeadlock);
+        }
+        if checked {
+            if let Some(max_waiters) = self.state.max_waiters {
+                if state.pending_len() >= max_waiters {
+                    debug_log!("LockerManager:too many waiters for locker {:?}", locker_id);
+                    return Err(LockerError::WouldQueueTooDeep);
+                }
+            }
+        }
+
+        let waiter_id = self.state.waiter_seq.fetch_add(1, Ordering::Relaxed);
+        let future = NotifyFuture::new();
+        let waiter = LockerWaiter {
+            waiter_id,
+            owner,
+            priority,
+            overtaken: 0,
+            signal: WaiterSignal::Future(future.clone()),
+        };
+        match kind {
+            LockKind::Write => state.push_writer(waiter, self.state.max_overtakes),
+            LockKind::Read => state.pending_readers.push_back(waiter),
+            LockKind::Permit => state.pending_permits.push_back(waiter),
+            LockKind::Upgradeable => state.pending_upgradeable.push_back(waiter),
+            LockKind::Upgrade => state.pending_upgrade.push_back(waiter),
+        }
+        #[cfg(feature = "metrics")]
+        self.record_queued(state.pending_len());
+        #[cfg(feature = "deadlock-detection")]
+        if let Some(owner) = owner {
+            if let Some(cycle) = Self::find_wait_cycle(&locker_map, locker_id, owner) {
+                log::error!("LockerManager:deadlock detected, owner {:?} waiting locker {:?} cycle {:?}", owner, locker_id, cycle);
+            }
+        }
+        Ok(Some(PendingLocker {
+            manager: self,
+            locker_id,
+            kind,
+            waiter_id,
+            future,
+            acquired: false,
+        }))
+    }
+
+    // Follows holder -> locker it waits for from `locker_id` and returns the lockers on the way if that leads
+    // back to `owner`. Only owned write lockers take part, readers and plain lockers have no owner to follow.
+    #[cfg(feature = "deadlock-detection")]
+    fn find_wait_cycle(locker_map: &LockerMap<K>, locker_id: &HashedKey<K>, owner: LockOwner) -> Option<Vec<K>> {
+        let mut cycle = vec![locker_id.key.clone()];
+        let mut current = locker_id.clone();
+        loop {
+            let holder = locker_map.get(&current)?.owner?;
+            if holder == owner {
+                return Some(cycle);
+            }
+            let (next, _) = locker_map.iter().find(|(_, state)| {
+                state.pending_list.iter().any(|waiter| waiter.owner == Some(holder))
+            })?;
+            if cycle.contains(&next.key) {
+                // a cycle we are not part of, it was reported when it formed
+                return None;
+            }
+            cycle.push(next.key.clone());
+            current = next.clone();
+        }
+    }
+
+    // Called when a waiter is dropped before it observed the locker. If it is still queued it just leaves
+    // the queue, otherwise the locker has already been handed to it and must be passed on.
+    fn cancel_wait(&self, locker_id: &HashedKey<K>, kind: LockKind, waiter_id: u64) {
+        let mut locker_map = self.state.locker_map.lock_recover();
+        if let Some(state) = locker_map.get_mut(locker_id) {
+            let pending_list = match kind {
+                LockKind::Write => &mut state.pending_list,
+                LockKind::Read => &mut state.pending_readers,
+                LockKind::Permit => &mut state.pending_permits,
+                LockKind::Upgradeable => &mut state.pending_upgradeable,
+                LockKind::Upgrade => &mut state.pending_upgrade,
+            };
+            let pos = pending_list.iter().position(|waiter| waiter.waiter_id == waiter_id);
+            if let Some(pos) = pos {
+                pending_list.remove(pos);
+                if kind == LockKind::Upgrade {
+                    // the upgradeable locker was given up for the upgrade, so its read goes too
+                    state.release_upgradeable();
+                }
+                // readers queued behind a cancelled writer may be able to go now
+                state.wake_waiters(false);
+                if state.is_free() {
+                    locker_map.remove(locker_id);
+                }
+                self.notify_drained(&locker_map);
+                drop(locker_map);
+                if kind == LockKind::Upgrade {
+                    self.notify_release(locker_id);
+                }
+                debug_log!("LockerManager:cancel waiting locker {:?}", locker_id);
+                return;
+            }
+        }
+        drop(locker_map);
+        // the waiter never reported the locker as acquired, so it isn't reported as released either, except
+        // an upgrade whose upgradeable locker was reported before
+        match kind {
+            LockKind::Write => self.unlock_hashed(locker_id, false),
+            LockKind::Read => self.unlock_read_hashed(locker_id, false),
+            LockKind::Permit => self.unlock_permit_hashed(locker_id, false),
+            LockKind::Upgradeable => self.unlock_upgradeable_hashed(locker_id, false),
+            LockKind::Upgrade => self.unlock_hashed(locker_id, true),
+        }
+    }
+
+    // Takes a queued writer out of the queue, or returns None if it isn't queued (anymore).
+    fn leave_write_queue(&self, locker_map: &mut LockerMap<K>, locker_id: &HashedKey<K>, waiter_id: u64) -> Option<LockerWaiter> {
+        let state = locker_map.get_mut(locker_id)?;
+        let pos = state.pending_list.iter().position(|waiter| waiter.waiter_id == waiter_id)?;
+        let waiter = state.pending_list.remove(pos);
+        state.wake_waiters(false);
+        if state.is_free() {
+            locker_map.remove(locker_id);
+        }
+        self.notify_drained(locker_map);
+        waiter
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> LockerMetrics {
+        *self.state.metrics.lock_recover()
+    }
+
+    #[cfg(feature = "metrics")]
+    fn record_immediate(&self) {
+        let mut metrics = self.state.metrics.lock_recover();
+        metrics.acquisitions += 1;
+        metrics.immediate_acquisitions += 1;
+    }
+
+    #[cfg(feature = "metrics")]
+    fn record_queued(&self, pending_len: usize) {
+        let mut metrics = self.state.metrics.lock_recover();
+        metrics.contended_acquisitions += 1;
+        metrics.max_pending_depth = metrics.max_pending_depth.max(pending_len);
+    }
+
+    // Held in either mode, an id nobody holds or waits for is not tracked at all.
+    pub fn is_locked(&self, locker_id: &K) -> bool {
+        self.is_locked_hashed(&self.hashed(locker_id.clone()))
+    }
+
+    fn is_locked_hashed(&self, locker_id: &HashedKey<K>) -> bool {
+        self.state.locker_map.lock_recover().get(locker_id).map(|state| state.is_held()).unwrap_or(false)
+    }
+
+    // From now on the `_checked` acquisitions fail with `LockerError::ShuttingDown`, and `try_lock` and
+    // `try_get_locker` fail as if the locker were taken. Holders and waiters already queued carry on as
+    // usual. The plain acquisitions (`get_locker`, `lock`, `get_read_locker`, ...) can't report an error
+    // and still hand out lockers, unless the manager was built with `panic_after_shutdown`. Callers that
+    // must be refused use their `_checked` variants.
+    pub fn begin_shutdown(&self) {
+        self.state.shutting_down.store(true, Ordering::SeqCst);
+        debug_log!("LockerManager:begin shutdown");
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.shutting_down.load(Ordering::SeqCst)
+    }
+
+    // Called for a plain acquisition during shutdown.
+    fn refuse_after_shutdown(&self, locker_id: &HashedKey<K>) {
+        if self.state.panic_after_shutdown {
+            panic!("LockerManager:locker {:?} requested after begin_shutdown, use the _checked variant", locker_id);
+        }
+    }
+
+    // Resolves once no locker is held or waited for.
+    pub async fn await_drained(&self) {
+        let future = {
+            let locker_map = self.state.locker_map.lock_recover();
+            if Self::is_drained(&locker_map) {
+                return;
+            }
+            let future = NotifyFuture::new();
+            self.state.drain_waiters.lock_recover().push(future.clone());
+            future
+        };
+        future.await
+    }
+
+    fn is_drained(locker_map: &LockerMap<K>) -> bool {
+        locker_map.values().all(|state| !state.is_held() && state.pending_len() == 0)
+    }
+
+    fn notify_drained(&self, locker_map: &LockerMap<K>) {
+        let mut drain_waiters = self.state.drain_waiters.lock_recover();
+        if !drain_waiters.is_empty() && Self::is_drained(locker_map) {
+            for future in drain_waiters.drain(..) {
+                future.set_complete(());
+            }
+        }
+    }
+
+    pub fn is_poisoned(&self, locker_id: &K) -> bool {
+        let locker_id = self.hashed(locker_id.clone());
+        self.state.locker_map.lock_recover().get(&locker_id).map(|state| state.poisoned).unwrap_or(false)
+    }
+
+    pub fn clear_poison(&self, locker_id: &K) {
+        let locker_id = self.hashed(locker_id.clone());
+        let mut locker_map = self.state.locker_map.lock_recover();
+        if let Some(state) = locker_map.get_mut(&locker_id) {
+            state.poisoned = false;
+            if state.is_free() {
+                locker_map.remove(&locker_id);
+            }
+        }
+    }
+
+    fn poison(&self, locker_id: &HashedKey<K>) {
+        if let Some(state) = self.state.locker_map.lock_recover().get_mut(locker_id) {
+            state.poisoned = true;
+            log::warn!("LockerManager:poison locker {:?}", locker_id);
+        }
+    }
+
+    pub fn pending_count(&self, locker_id: &K) -> usize {
+        let locker_id = self.hashed(locker_id.clone());
+        self.state.locker_map.lock_recover().get(&locker_id).map(|state| state.pending_len()).unwrap_or(0)
+    }
+
+    pub fn tracked_keys(&self) -> Vec<K> {
+        self.state.locker_map.lock_recover().keys().map(|locker_id| locker_id.key.clone()).collect()
+    }
+
+    // Ids that are held, waited for or poisoned, without copying them like `tracked_keys`.
+    pub fn tracked_count(&self) -> usize {
+        self.state.locker_map.lock_recover().len()
+    }
+
+    // Ids whose write side has been held for longer than `threshold`, with how long, e.g. to report
+    // holders that got stuck. A reentrant locker counts from its outermost acquisition.
+    pub fn held_longer_than(&self, threshold: Duration) -> Vec<(K, Duration)> {
+        self.state.locker_map.lock_recover().iter().filter_map(|(locker_id, state)| {
+            let held = state.locked_at?.elapsed();
+            (held > threshold).then(|| (locker_id.key.clone(), held))
+        }).collect()
+    }
+
+    // Number of unlock calls for lockers that were not locked, only tracked in debug builds.
+    #[cfg(debug_assertions)]
+    pub fn invalid_unlock_count(&self) -> u64 {
+        self.state.invalid_unlock_count.load(Ordering::Relaxed)
+    }
+
+    pub fn unlock(&self, locker_id: &K) {
+        self.unlock_hashed(&self.hashed(locker_id.clone()), true)
+    }
+
+    // `observed` is false for a locker handed to a waiter that was dropped before it reported the locker as
+    // acquired, the observer then sees neither side.
+    fn unlock_hashed(&self, locker_id: &HashedKey<K>, observed: bool) {
+        let mut locker_map = self.state.locker_map.lock_recover();
+        match locker_map.get_mut(locker_id) {
+            Some(state) if state.is_locked => {
+                if state.lock_count > 1 {
+                    state.lock_count -= 1;
+                    debug_log!("LockerManager:leave locker {:?} count {}", locker_id, state.lock_count);
+                    drop(locker_map);
+                    if observed {
+                        self.notify_release(locker_id);
+                    }
+                    return;
+                }
+                #[cfg(feature = "hold-warnings")]
+                self.warn_long_hold(locker_id, state);
+                state.release_write(self.state.phase_fair);
+                if state.is_free() {
+                    // nobody is waiting, drop the entry so that the map doesn't grow with every id ever locked
+                    locker_map.remove(locker_id);
+                }
+                self.notify_drained(&locker_map);
+            }
+            _ => {
+                self.invalid_unlock(locker_id);
+                return;
+            }
+        }
+        drop(locker_map);
+        if observed {
+            self.notify_release(locker_id);
+        }
+        debug_log!("LockerManager:free locker {:?}", locker_id);
+    }
+
+    #[cfg(feature = "hold-warnings")]
+    fn warn_long_hold(&self, locker_id: &HashedKey<K>, state: &LockerState) {
+        let (Some(warn_after), Some(locked_at)) = (self.state.warn_after, state.locked_at) else {
+            return;
+        };
+        let held = locked_at.elapsed();
+        if held <= warn_after {
+            return;
+        }
+        match &state.backtrace {
+            Some(backtrace) if backtrace.status() == std::backtrace::BacktraceStatus::Captured => {
+                log::warn!("LockerManager:locker {:?} held for {:?}, taken at\n{}", locker_id, held, backtrace);
+            }
+            _ => log::warn!("LockerManager:locker {:?} held for {:?}", locker_id, held),
+        }
+    }
+
+    // Releases a TTL locker unless the write side was released since it was taken in `epoch`, so
+    // whichever of the expiry and the locker's drop comes second does nothing.
+    fn unlock_epoch(&self, locker_id: &HashedKey<K>, epoch: u64) {
+        let mut locker_map = self.state.locker_map.lock_recover();
+        match locker_map.get_mut(locker_id) {
+            Some(state) if state.is_locked && state.epoch == epoch => {
+                #[cfg(feature = "hold-warnings")]
+                self.warn_long_hold(locker_id, state);
+                state.release_write(self.state.phase_fair);
+                if state.is_free() {
+                    locker_map.remove(locker_id);
+                }
+                self.notify_drained(&locker_map);
+            }
+            _ => {
+                debug_log!("LockerManager:locker {:?} already released", locker_id);
+                return;
+            }
+        }
+        drop(locker_map);
+        self.notify_release(locker_id);
+        debug_log!("LockerManager:free locker {:?}", locker_id);
+    }
+
+    pub fn unlock_read(&self, locker_id: &K) {
+        self.unlock_read_hashed(&self.hashed(locker_id.clone()), true)
+    }
+
+    fn unlock_read_hashed(&self, locker_id: &HashedKey<K>, observed: bool) {
+        let mut locker_map = self.state.locker_map.lock_recover();
+        match locker_map.get_mut(locker_id) {
+            Some(state) if state.readers > 0 => {
+                state.readers -= 1;
+                state.wake_waiters(false);
+                if state.is_free() {
+                    locker_map.remove(locker_id);
+                }
+                self.notify_drained(&locker_map);
+            }
+            _ => {
+                self.invalid_unlock(locker_id);
+                return;
+            }
+        }
+        drop(locker_map);
+        if observed {
+            self.notify_release(locker_id);
+        }
+        debug_log!("LockerManager:free read locker {:?}", locker_id);
+    }
+
+    pub fn unlock_permit(&self, locker_id: &K) {
+        self.unlock_permit_hashed(&self.hashed(locker_id.clone()), true)
+    }
+
+    fn unlock_permit_hashed(&self, locker_id: &HashedKey<K>, observed: bool) {
+        let mut locker_map = self.state.locker_map.lock_recover();
+        match locker_map.get_mut(locker_id) {
+            Some(state) if state.permits > 0 => {
+                state.permits -= 1;
+                state.wake_waiters(false);
+                if state.is_free() {
+                    locker_map.remove(locker_id);
+                }
+                self.notify_drained(&locker_map);
+            }
+            _ => {
+                self.invalid_unlock(locker_id);
+                return;
+            }
+        }
+        drop(locker_map);
+        if observed {
+            self.notify_release(locker_id);
+        }
+        debug_log!("LockerManager:free permit {:?}", locker_id);
+    }
+
+    fn unlock_upgradeable_hashed(&self, locker_id: &HashedKey<K>, observed: bool) {
+        let mut locker_map = self.state.locker_map.lock_recover();
+        match locker_map.get_mut(locker_id) {
+            Some(state) if state.upgradeable => {
+                state.release_upgradeable();
+                if state.is_free() {
+                    locker_map.remove(locker_id);
+                }
+                self.notify_drained(&locker_map);
+            }
+            _ => {
+                self.invalid_unlock(locker_id);
+                return;
+            }
+        }
+        drop(locker_map);
+        if observed {
+            self.notify_release(locker_id);
+        }
+        debug_log!("LockerManager:free upgradeable locker {:?}", locker_id);
+    }
+
+    pub fn set_observer(&self, observer: Arc<dyn LockerObserver<K>>) {
+        *self.state.observer.lock_recover() = Some(observer);
+    }
+
+    fn observer(&self) -> Option<Arc<dyn LockerObserver<K>>> {
+        self.state.observer.lock_recover().clone()
+    }
+
+    fn notify_acquire(&self, locker_id: &HashedKey<K>, waited: Duration) {
+        if let Some(observer) = self.observer() {
+            observer.on_acquire(&locker_id.key, waited);
+        }
+    }
+
+    fn notify_release(&self, locker_id: &HashedKey<K>) {
+        if let Some(observer) = self.observer() {
+            observer.on_release(&locker_id.key);
+        }
+    }
+
+    fn invalid_unlock(&self, locker_id: &HashedKey<K>) {
+        log::warn!("LockerManager:unlock unknown locker {:?}", locker_id);
+        #[cfg(debug_assertions)]
+        self.state.invalid_unlock_count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// A queued lock request. Dropping it before `wait` completes gives up the place in the queue, so a
+// cancelled `lock` future never leaves the locker handed to nobody.
+struct PendingLocker<'a, K: Hash + Eq + Clone + Debug, S: BuildHasher> {
+    manager: &'a LockerManager<K, S>,
+    locker_id: &'a HashedKey<K>,
+    kind: LockKind,
+    waiter_id: u64,
+    future: NotifyFuture<()>,
+    acquired: bool,
+}
+
+impl <'a, K: Hash + Eq + Clone + Debug, S: BuildHasher> PendingLocker<'a, K, S> {
+    async fn wait(mut self) {
+        self.future.clone().await;
+        self.acquired = true;
+        #[cfg(feature = "metrics")]
+        {
+            self.manager.state.metrics.lock_recover().acquisitions += 1;
+        }
+    }
+}
+
+impl <'a, K: Hash + Eq + Clone + Debug, S: BuildHasher> Drop for PendingLocker<'a, K, S> {
+    fn drop(&mut self) {
+        if !self.acquired {
+            self.manager.cancel_wait(self.locker_id, self.kind, self.waiter_id);
+        }
+    }
+}
+
+type LockHandle<K, S> = (AcquireFuture<K, S>, WaitTicket<K, S>);
+
+// Resolves to the locker once it is handed over, or to None if the request was cancelled through its
+// `WaitTicket`. Dropped before that, it leaves the queue like any other waiter.
+pub struct AcquireFuture<K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    manager: LockerManager<K, S>,
+    locker_id: HashedKey<K>,
+    // None when the locker was free and taken right away
+    waiter: Option<(u64, NotifyFuture<()>)>,
+    cancelled: Arc<AtomicBool>,
+    start: Instant,
+    done: bool,
+}
+
+// Nothing in the future is pinned, the locker id is only ever moved out by clone.
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Unpin for AcquireFuture<K, S> {}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Future for AcquireFuture<K, S> {
+    type Output = Option<Locker<K, S>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        assert!(!this.done, "AcquireFuture polled after completion");
+        if let Some((_, future)) = &mut this.waiter {
+            if Pin::new(future).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(this.complete())
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> AcquireFuture<K, S> {
+    // Called once the locker was handed over or the request cancelled.
+    fn complete(&mut self) -> Option<Locker<K, S>> {
+        self.done = true;
+        if self.cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        #[cfg(feature = "metrics")]
+        if self.waiter.is_some() {
+            self.manager.state.metrics.lock_recover().acquisitions += 1;
+        }
+        debug_log!("LockerManager:get locker {:?}", self.locker_id);
+        self.manager.notify_acquire(&self.locker_id, self.start.elapsed());
+        let locker = Locker::new(self.manager.clone(), self.locker_id.clone());
+        Some(locker.with_contended(self.waiter.is_some()))
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Drop for AcquireFuture<K, S> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Some((waiter_id, _)) = self.waiter {
+            // decided under the map's lock, so a concurrent cancel can't make the locker look handed over
+            let mut locker_map = self.manager.state.locker_map.lock_recover();
+            if self.cancelled.load(Ordering::Relaxed) {
+                return;
+            }
+            if self.manager.leave_write_queue(&mut locker_map, &self.locker_id, waiter_id).is_some() {
+                debug_log!("LockerManager:cancel waiting locker {:?}", self.locker_id);
+                return;
+            }
+        }
+        // the locker was already handed to the future, pass it on. Only `complete` reports it as acquired, so
+        // the release isn't reported either.
+        self.manager.unlock_hashed(&self.locker_id, false);
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Debug for AcquireFuture<K, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AcquireFuture").field("id", &self.locker_id).finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum TryOrWait<K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    Locked(Locker<K, S>),
+    // already queued, awaiting it gets the locker
+    Wait(LockAcquire<K, S>),
+}
+
+// An `AcquireFuture` nobody holds the ticket of, so it can't be cancelled.
+#[derive(Debug)]
+pub struct LockAcquire<K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    future: AcquireFuture<K, S>,
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Future for LockAcquire<K, S> {
+    type Output = Locker<K, S>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.future).poll(cx).map(|locker| locker.expect("only a wait ticket cancels a request"))
+    }
+}
+
+pub struct WaitTicket<K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    manager: LockerManager<K, S>,
+    locker_id: HashedKey<K>,
+    waiter_id: Option<u64>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> WaitTicket<K, S> {
+    // How many writers are queued ahead of the request, None once it got the locker or was cancelled.
+    pub fn position(&self) -> Option<usize> {
+        let waiter_id = self.waiter_id?;
+        let locker_map = self.manager.state.locker_map.lock_recover();
+        locker_map.get(&self.locker_id)?.pending_list.iter().position(|waiter| waiter.waiter_id == waiter_id)
+    }
+
+    // Takes the request out of the queue and resolves its future to None. Returns false if it already
+    // got the locker or was cancelled before.
+    pub fn cancel(&self) -> bool {
+        let Some(waiter_id) = self.waiter_id else {
+            return false;
+        };
+        let mut locker_map = self.manager.state.locker_map.lock_recover();
+        let Some(waiter) = self.manager.leave_write_queue(&mut locker_map, &self.locker_id, waiter_id) else {
+            return false;
+        };
+        self.cancelled.store(true, Ordering::Relaxed);
+        drop(locker_map);
+        waiter.signal.complete();
+        debug_log!("LockerManager:cancel waiting locker {:?}", self.locker_id);
+        true
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Debug for WaitTicket<K, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WaitTicket").field("id", &self.locker_id).finish()
+    }
+}
+
+// The manager's keyed operations on an id whose hash was computed once when the handle was made,
+// like an entry of the locker map.
+pub struct KeyHandle<'a, K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    manager: &'a LockerManager<K, S>,
+    locker_id: HashedKey<K>,
+}
+
+impl <'a, K: Hash + Eq + Clone + Debug, S: BuildHasher> KeyHandle<'a, K, S> {
+    pub fn id(&self) -> &K {
+        &self.locker_id.key
+    }
+
+    pub async fn get_locker(&self) -> Locker<K, S> {
+        let contended = self.manager.acquire_unchecked(&self.locker_id, LockRequest::write()).await;
+        Locker::new(self.manager.clone(), self.locker_id.clone()).with_contended(contended)
+    }
+
+    pub async fn lock(&self) {
+        self.manager.acquire_unchecked(&self.locker_id, LockRequest::write()).await;
+    }
+
+    pub fn try_lock(&self) -> bool {
+        self.manager.try_lock_hashed(&self.locker_id)
+    }
+
+    pub fn unlock(&self) {
+        self.manager.unlock_hashed(&self.locker_id, true)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.manager.is_locked_hashed(&self.locker_id)
+    }
+}
+
+impl <'a, K: Hash + Eq + Clone + Debug, S: BuildHasher> Debug for KeyHandle<'a, K, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyHandle").field("id", &self.locker_id).finish()
+    }
+}
+
+type ReleaseHook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+// A locker owns its id and a handle to its manager, so it is 'static and can be moved into a spawned
+// task, it is released wherever it is finally dropped. Lockers, the other guards and the futures that
+// acquire them are Send and Sync whenever the key and the hasher are, `test_auto_traits` keeps it so.
+pub struct Locker<K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    manager: LockerManager<K, S>,
+    locker_id: HashedKey<K>,
+    // set for TTL lockers, which may have been released by their expiry already
+    epoch: Option<u64>,
+    contended: bool,
+    // set for claims, releases the locker in place of the drop
+    delayed_release: Option<Box<dyn FnOnce() + Send + Sync>>,
+    release_hook: Option<ReleaseHook>,
+    #[cfg(feature = "tracing")]
+    _span: tracing::Span,
+}
+
+impl Locker {
+    pub async fn get_locker(locker_id: impl Into<LockId>) -> Self {
+        LOCK_MANAGER.get_locker(LockId::key(locker_id)).await
+    }
+
+    pub async fn get_locker_checked(locker_id: impl Into<LockId>) -> Result<Self, LockerError> {
+        LOCK_MANAGER.get_locker_checked(LockId::key(locker_id)).await
+    }
+
+    // Shuts down the global manager, see `LockerManager::begin_shutdown`. It is shared by the whole
+    // program, libraries using it are refused as well.
+    pub fn begin_shutdown() {
+        LOCK_MANAGER.begin_shutdown()
+    }
+
+    pub fn is_shutting_down() -> bool {
+        LOCK_MANAGER.is_shutting_down()
+    }
+
+    pub async fn await_drained() {
+        LOCK_MANAGER.await_drained().await
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn get_locker_with_ttl(locker_id: impl Into<LockId>, ttl: Duration) -> Self {
+        LOCK_MANAGER.get_locker_with_ttl(LockId::key(locker_id), ttl).await
+    }
+
+    pub async fn get_locker_prio(locker_id: impl Into<LockId>, priority: Priority) -> Self {
+        LOCK_MANAGER.get_locker_prio(LockId::key(locker_id), priority).await
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn claim(locker_id: impl Into<LockId>, hold_after_release: Duration) -> Self {
+        LOCK_MANAGER.claim(LockId::key(locker_id), hold_after_release).await
+    }
+
+    pub async fn get_locker_timed(locker_id: impl Into<LockId>) -> (Self, Duration) {
+        LOCK_MANAGER.get_locker_timed(LockId::key(locker_id)).await
+    }
+
+    pub async fn with_locker<F, Fut, R>(locker_id: impl Into<LockId>, f: F) -> R
+        where F: FnOnce() -> Fut, Fut: Future<Output = R> {
+        LOCK_MANAGER.with_locker(LockId::key(locker_id), f).await
+    }
+
+    pub async fn get_locker_poisonable(locker_id: impl Into<LockId>) -> LockResult<Self> {
+        LOCK_MANAGER.get_locker_poisonable(LockId::key(locker_id)).await
+    }
+
+    pub fn try_or_wait(locker_id: impl Into<LockId>) -> TryOrWait {
+        LOCK_MANAGER.try_or_wait(LockId::key(locker_id))
+    }
+
+    pub fn lock_acquire(locker_id: impl Into<LockId>) -> LockAcquire {
+        LOCK_MANAGER.lock_acquire(LockId::key(locker_id))
+    }
+
+    pub fn try_get_locker(locker_id: impl Into<LockId>) -> Option<Self> {
+        LOCK_MANAGER.try_get_locker(LockId::key(locker_id))
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn get_locker_spin(locker_id: impl Into<LockId>, max_attempts: usize, backoff: Duration) -> Option<Self> {
+        LOCK_MANAGER.get_locker_spin(LockId::key(locker_id), max_attempts, backoff).await
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn get_locker_timeout(locker_id: impl Into<LockId>, timeout: Duration) -> Option<Self> {
+        LOCK_MANAGER.get_locker_timeout(LockId::key(locker_id), timeout).await
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn get_or_else<R>(locker_id: impl Into<LockId>, timeout: Duration, fallback: impl FnOnce() -> R) -> Result<Self, R> {
+        LOCK_MANAGER.get_or_else(LockId::key(locker_id), timeout, fallback).await
+    }
+
+    pub async fn get_locker_timeout_with(locker_id: impl Into<LockId>, sleep: impl Future<Output = ()>) -> Option<Self> {
+        LOCK_MANAGER.get_locker_timeout_with(LockId::key(locker_id), sleep).await
+    }
+
+    pub async fn get_reentrant_locker(locker_id: impl Into<LockId>, owner: LockOwner) -> Self {
+        LOCK_MANAGER.get_reentrant_locker(LockId::key(locker_id), owner).await
+    }
+
+    pub async fn get_locker_with_owner(locker_id: impl Into<LockId>, owner: LockOwner) -> Self {
+        LOCK_MANAGER.get_locker_with_owner(LockId::key(locker_id), owner).await
+    }
+
+    pub async fn get_locker_with_owner_checked(locker_id: impl Into<LockId>, owner: LockOwner) -> Result<Self, LockerError> {
+        LOCK_MANAGER.get_locker_with_owner_checked(LockId::key(locker_id), owner).await
+    }
+
+    pub async fn get_lockers(locker_ids: Vec<impl Into<LockId>>) -> Vec<Self> {
+        LOCK_MANAGER.get_lockers(locker_ids.into_iter().map(LockId::key).collect()).await
+    }
+
+    pub async fn get_read_locker(locker_id: impl Into<LockId>) -> ReadLocker {
+        LOCK_MANAGER.get_read_locker(LockId::key(locker_id)).await
+    }
+
+    pub async fn get_read_locker_checked(locker_id: impl Into<LockId>) -> Result<ReadLocker, LockerError> {
+        LOCK_MANAGER.get_read_locker_checked(LockId::key(locker_id)).await
+    }
+
+    pub async fn get_write_locker(locker_id: impl Into<LockId>) -> WriteLocker {
+        LOCK_MANAGER.get_write_locker(LockId::key(locker_id)).await
+    }
+
+    pub async fn get_upgradeable_locker(locker_id: impl Into<LockId>) -> UpgradeableLocker {
+        LOCK_MANAGER.get_upgradeable_locker(LockId::key(locker_id)).await
+    }
+
+    pub async fn get_upgradeable_locker_checked(locker_id: impl Into<LockId>) -> Result<UpgradeableLocker, LockerError> {
+        LOCK_MANAGER.get_upgradeable_locker_checked(LockId::key(locker_id)).await
+    }
+
+    pub async fn get_semaphore(locker_id: impl Into<LockId>, permits_available: usize) -> SemaphorePermit {
+        LOCK_MANAGER.get_semaphore(LockId::key(locker_id), permits_available).await
+    }
+
+    pub async fn get_semaphore_checked(locker_id: impl Into<LockId>, permits_available: usize) -> Result<SemaphorePermit, LockerError> {
+        LOCK_MANAGER.get_semaphore_checked(LockId::key(locker_id), permits_available).await
+    }
+
+    pub fn with_namespace(namespace: impl Into<LockId>) -> LockerNamespace {
+        LockerNamespace::new(LockId::key(namespace))
+    }
+}
+
+// Global lockers whose ids are prefixed with `namespace:`, so a library's ids can't collide with the
+// ones other users of the global manager pick. A trailing `:` of the namespace is not repeated.
+#[derive(Clone, Debug)]
+pub struct LockerNamespace {
+    namespace: String,
+}
+
+impl LockerNamespace {
+    fn new(mut namespace: String) -> Self {
+        while namespace.ends_with(':') {
+            namespace.pop();
+        }
+        Self {
+            namespace
+        }
+    }
+
+    // Namespaces nest, `Locker::with_namespace("a").namespace("b")` locks ids of the form `a:b:id`.
+    pub fn namespace(&self, namespace: impl Into<LockId>) -> Self {
+        Self::new(self.id(namespace))
+    }
+
+    // The id the global manager knows the namespaced `locker_id` by.
+    pub fn id(&self, locker_id: impl Into<LockId>) -> String {
+        format!("{}:{}", self.namespace, locker_id.into().as_str())
+    }
+
+    pub async fn get_locker(&self, locker_id: impl Into<LockId>) -> Locker {
+        Locker::get_locker(self.id(locker_id)).await
+    }
+
+    pub fn try_get_locker(&self, locker_id: impl Into<LockId>) -> Option<Locker> {
+        Locker::try_get_locker(self.id(locker_id))
+    }
+}
+
+// The id the global lockers take. Everything `Locker::get_locker` took before converts into it, and
+// `LockId::new`, or `locker_id!("id")`, makes one in a const without allocating. The global manager stays
+// keyed by `String`, so the id is turned into one when it is locked, as a `&str` was before.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct LockId(Cow<'static, str>);
+
+impl LockId {
+    pub const fn new(locker_id: &'static str) -> Self {
+        Self(Cow::Borrowed(locker_id))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    fn key(locker_id: impl Into<LockId>) -> String {
+        locker_id.into().into()
+    }
+}
+
+impl From<&str> for LockId {
+    fn from(locker_id: &str) -> Self {
+        Self(Cow::Owned(locker_id.to_string()))
+    }
+}
+
+impl From<&String> for LockId {
+    fn from(locker_id: &String) -> Self {
+        Self(Cow::Owned(locker_id.clone()))
+    }
+}
+
+impl From<String> for LockId {
+    fn from(locker_id: String) -> Self {
+        Self(Cow::Owned(locker_id))
+    }
+}
+
+impl From<LockId> for String {
+    fn from(locker_id: LockId) -> Self {
+        locker_id.0.into_owned()
+    }
+}
+
+// Declares a locker id type for one domain, `locker_id!(pub struct UserId);`, so that a
+// `LockerManager<UserId>` can't be handed an order id by mistake. The id is a `Cow<'static, str>`,
+// `UserId::new` is const and doesn't allocate for ids known at compile time. `locker_id!("id")` is the
+// const `LockId` of the global lockers.
+#[macro_export]
+macro_rules! locker_id {
+    ($locker_id:literal) => {
+        $crate::LockId::new($locker_id)
+    };
+    ($(#[$attr:meta])* $vis:vis struct $name:ident;) => {
+        $(#[$attr])*
+        #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+        $vis struct $name(std::borrow::Cow<'static, str>);
+
+        impl $name {
+            pub const fn new(locker_id: &'static str) -> Self {
+                Self(std::borrow::Cow::Borrowed(locker_id))
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl From<&'static str> for $name {
+            fn from(locker_id: &'static str) -> Self {
+                Self::new(locker_id)
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(locker_id: String) -> Self {
+                Self(std::borrow::Cow::Owned(locker_id))
+            }
+        }
+    };
+}
+
+// Holds the global locker of an id for the rest of the enclosing scope, `named_lock!(id);`, or just
+// while a block runs, `named_lock!(id => { ... })`, which evaluates to the block's value. Only usable
+// in async code, the guard has no name so it can't be dropped early by mistake.
+#[macro_export]
+macro_rules! named_lock {
+    ($locker_id:expr => $body:block) => {{
+        let _locker = $crate::Locker::get_locker($locker_id).await;
+        $body
+    }};
+    ($locker_id:expr) => {
+        let _locker = $crate::Locker::get_locker($locker_id).await;
+    };
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Locker<K, S> {
+    fn new(manager: LockerManager<K, S>, locker_id: HashedKey<K>) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            _span: tracing::debug_span!("locker.held", id = ?locker_id),
+            manager,
+            locker_id,
+            epoch: None,
+            contended: false,
+            delayed_release: None,
+            release_hook: None,
+        }
+    }
+
+    fn with_contended(mut self, contended: bool) -> Self {
+        self.contended = contended;
+        self
+    }
+
+    pub fn id(&self) -> &K {
+        &self.locker_id.key
+    }
+
+    // Whether the locker was held by someone else when it was asked for and the caller had to wait.
+    pub fn was_contended(&self) -> bool {
+        self.contended
+    }
+
+    // Async cleanup that must finish before the next holder gets the locker, e.g. a flush. It only runs
+    // through `release`, a plain drop can't await it and skips it.
+    pub fn set_release_hook<F, Fut>(&mut self, hook: F)
+        where F: FnOnce() -> Fut + Send + Sync + 'static, Fut: Future<Output = ()> + Send + 'static {
+        self.release_hook = Some(Box::new(move || Box::pin(hook())));
+    }
+
+    // Runs the release hook, if any, while still holding the locker and then releases it.
+    pub async fn release(mut self) {
+        if let Some(hook) = self.release_hook.take() {
+            hook().await;
+        }
+    }
+
+    // A locker dropped while its thread unwinds poisons itself, but runtimes catch a task's panic and
+    // drop the task afterwards. Running the critical section through here poisons the locker as soon as
+    // the future panics, the panic then continues as usual.
+    pub async fn poison_on_panic<F: Future>(&self, future: F) -> F::Output {
+        let mut future = pin!(future);
+        std::future::poll_fn(|cx| {
+            match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+                Ok(Poll::Ready(output)) => Poll::Ready(output),
+                Ok(Poll::Pending) => Poll::Pending,
+                Err(panic) => {
+                    self.manager.poison(&self.locker_id);
+                    std::panic::resume_unwind(panic)
+                }
+            }
+        }).await
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Debug for Locker<K, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Locker").field("id", &self.locker_id).finish()
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Drop for Locker<K, S> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.manager.poison(&self.locker_id);
+        }
+        if let Some(release) = self.delayed_release.take() {
+            release();
+            return;
+        }
+        match self.epoch {
+            Some(epoch) => self.manager.unlock_epoch(&self.locker_id, epoch),
+            None => self.manager.unlock_hashed(&self.locker_id, true),
+        }
+    }
+}
+
+pub struct ReadLocker<K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    manager: LockerManager<K, S>,
+    locker_id: HashedKey<K>,
+    #[cfg(feature = "tracing")]
+    _span: tracing::Span,
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> ReadLocker<K, S> {
+    fn new(manager: LockerManager<K, S>, locker_id: HashedKey<K>) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            _span: tracing::debug_span!("locker.held", id = ?locker_id),
+            manager,
+            locker_id,
+        }
+    }
+
+    pub fn id(&self) -> &K {
+        &self.locker_id.key
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Debug for ReadLocker<K, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReadLocker").field("id", &self.locker_id).finish()
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Drop for ReadLocker<K, S> {
+    fn drop(&mut self) {
+        self.manager.unlock_read_hashed(&self.locker_id, true);
+    }
+}
+
+pub struct WriteLocker<K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    manager: LockerManager<K, S>,
+    locker_id: HashedKey<K>,
+    #[cfg(feature = "tracing")]
+    _span: tracing::Span,
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> WriteLocker<K, S> {
+    fn new(manager: LockerManager<K, S>, locker_id: HashedKey<K>) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            _span: tracing::debug_span!("locker.held", id = ?locker_id),
+            manager,
+            locker_id,
+        }
+    }
+
+    pub fn id(&self) -> &K {
+        &self.locker_id.key
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Debug for WriteLocker<K, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriteLocker").field("id", &self.locker_id).finish()
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Drop for WriteLocker<K, S> {
+    fn drop(&mut self) {
+        self.manager.unlock_hashed(&self.locker_id, true);
+    }
+}
+
+pub struct UpgradeableLocker<K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    manager: LockerManager<K, S>,
+    locker_id: HashedKey<K>,
+    // the read was turned into the write held by a `WriteLocker` now
+    upgraded: bool,
+    #[cfg(feature = "tracing")]
+    _span: tracing::Span,
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> UpgradeableLocker<K, S> {
+    fn new(manager: LockerManager<K, S>, locker_id: HashedKey<K>) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            _span: tracing::debug_span!("locker.held", id = ?locker_id),
+            manager,
+            locker_id,
+            upgraded: false,
+        }
+    }
+
+    pub fn id(&self) -> &K {
+        &self.locker_id.key
+    }
+
+    // Waits for the plain readers to leave and takes the write side without letting the read go, new
+    // readers wait meanwhile. Dropping the returned future before it completes releases the locker.
+    pub async fn upgrade(mut self) -> WriteLocker<K, S> {
+        self.upgraded = true;
+        self.manager.acquire_unchecked(&self.locker_id, LockRequest::upgrade()).await;
+        WriteLocker::new(self.manager.clone(), self.locker_id.clone())
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Debug for UpgradeableLocker<K, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpgradeableLocker").field("id", &self.locker_id).finish()
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Drop for UpgradeableLocker<K, S> {
+    fn drop(&mut self) {
+        if !self.upgraded {
+            self.manager.unlock_upgradeable_hashed(&self.locker_id, true);
+        }
+    }
+}
+
+pub struct SemaphorePermit<K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    manager: LockerManager<K, S>,
+    locker_id: HashedKey<K>,
+    #[cfg(feature = "tracing")]
+    _span: tracing::Span,
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> SemaphorePermit<K, S> {
+    fn new(manager: LockerManager<K, S>, locker_id: HashedKey<K>) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            _span: tracing::debug_span!("locker.held", id = ?locker_id),
+            manager,
+            locker_id,
+        }
+    }
+
+    pub fn id(&self) -> &K {
+        &self.locker_id.key
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Debug for SemaphorePermit<K, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SemaphorePermit").field("id", &self.locker_id).finish()
+    }
+}
+
+impl <K: Hash + Eq + Clone + Debug, S: BuildHasher> Drop for SemaphorePermit<K, S> {
+    fn drop(&mut self) {
+        self.manager.unlock_permit_hashed(&self.locker_id, true);
+    }
+}
+
+pub struct GuardObject<T, K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    locker: Locker<K, S>,
+    obj: T
+}
+
+impl <T> GuardObject<T> {
+    // Takes the global locker of `locker_id` and wraps `obj` in it, the same as
+    // `GuardObject::new(Locker::get_locker(locker_id).await, obj)`.
+    pub async fn acquire(locker_id: impl Into<LockId>, obj: T) -> Self {
+        Self::new(Locker::get_locker(locker_id).await, obj)
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug, S: BuildHasher> GuardObject<T, K, S> {
+    pub fn new(locker: Locker<K, S>, obj: T) -> Self {
+        Self {
+            locker,
+            obj
+        }
+    }
+
+    pub fn locker_id(&self) -> &K {
+        self.locker.id()
+    }
+
+    pub fn release_locker(self) -> T {
+        self.obj
+    }
+
+    pub fn into_parts(self) -> (Locker<K, S>, T) {
+        (self.locker, self.obj)
+    }
+
+    // Frees the locker right away while the caller keeps using the object.
+    pub fn unlock_keep(self) -> T {
+        let (locker, obj) = self.into_parts();
+        drop(locker);
+        obj
+    }
+}
+
+// Only the locker id is shown so that `T` doesn't have to be `Debug`.
+impl <T, K: Hash + Eq + Clone + Debug, S: BuildHasher> Debug for GuardObject<T, K, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GuardObject").field("locker_id", self.locker_id()).finish_non_exhaustive()
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug, S: BuildHasher> Deref for GuardObject<T, K, S> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.obj
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug, S: BuildHasher> DerefMut for GuardObject<T, K, S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.obj
+    }
+}
+
+// Guards kept in acquisition order that are released in reverse, last acquired first, while a plain
+// Vec drops its front first.
+pub struct LockSet<T, K: Hash + Eq + Clone + Debug = String, S: BuildHasher = RandomState> {
+    guards: Vec<GuardObject<T, K, S>>,
+}
+
+impl <T, K: Hash + Eq + Clone + Debug, S: BuildHasher> LockSet<T, K, S> {
+    pub fn new() -> Self {
+        Self {
+            guards: Vec::new()
+        }
+    }
+
+    pub fn push(&mut self, guard: GuardObject<T, K, S>) {
+        self.guards.push(guard);
+    }
+
+    pub fn len(&self) -> usize {
+        self.guards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.guards.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, GuardObject<T, K, S>> {
+        self.guards.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, GuardObject<T, K, S>> {
+        self.guards.iter_mut()
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug, S: BuildHasher> Default for LockSet<T, K, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug, S: BuildHasher> From<Vec<GuardObject<T, K, S>>> for LockSet<T, K, S> {
+    fn from(guards: Vec<GuardObject<T, K, S>>) -> Self {
+        Self {
+            guards
+        }
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug, S: BuildHasher> Debug for LockSet<T, K, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.guards.iter().map(|guard| guard.locker_id())).finish()
+    }
+}
+
+impl <T, K: Hash + Eq + Clone + Debug, S: BuildHasher> Drop for LockSet<T, K, S> {
+    fn drop(&mut self) {
+        while let Some(guard) = self.guards.pop() {
+            drop(guard);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::AssertUnwindSafe;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+    use std::time::Duration;
+    use crate::{GuardObject, LockAcquire, LockId, LockOwner, Locker, LockerError, LockerObserver, Priority, TryOrWait};
+    use crate::sync::{assert_send, assert_send_sync};
+    use super::LockerManager;
+
+    #[test]
+    fn test() {
+        async_std::task::block_on(async {
+            let _locker = Locker::get_locker("test".to_string()).await;
+            let i = Arc::new(Mutex::new(0));
+            let i_copy = i.clone();
+            async_std::task::spawn(async move {
+                let _locker = Locker::get_locker("test").await;
+                assert_eq!(*i_copy.lock().unwrap(), 1);
+            });
+            async_std::task::sleep(Duration::from_secs(5)).await;
+            *i.lock().unwrap() = 1;
+        });
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_timeout() {
+        async_std::task::block_on(async {
+            let locker = Locker::get_locker("test_timeout").await;
+            let ret = Locker::get_locker_timeout("test_timeout", Duration::from_millis(100)).await;
+            assert!(ret.is_none());
+
+            let waiter = async_std::task::spawn(async {
+                Locker::get_locker_timeout("test_timeout", Duration::from_secs(5)).await.is_some()
+            });
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            drop(locker);
+            assert!(waiter.await);
+
+            let ret = Locker::get_locker_timeout("test_timeout", Duration::from_millis(100)).await;
+            assert!(ret.is_some());
+        });
+    }
+
+    #[test]
+    fn test_try_get_locker() {
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let handles: Vec<_> = (0..2).map(|_| {
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                barrier.wait();
+                let locker = Locker::try_get_locker("test_try_get_locker");
+                barrier.wait();
+                locker.is_some()
+            })
+        }).collect();
+        let got: Vec<bool> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(got.iter().filter(|v| **v).count(), 1);
+
+        async_std::task::block_on(async {
+            let locker = Locker::get_locker("test_try_get_locker").await;
+            assert!(Locker::try_get_locker("test_try_get_locker").is_none());
+            drop(locker);
+            assert!(Locker::try_get_locker("test_try_get_locker").is_some());
+        });
+    }
+
+    #[test]
+    fn test_reclaim_entries() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            for i in 0..100000 {
+                manager.lock(format!("test_reclaim_entries_{}", i)).await;
+                manager.unlock(&format!("test_reclaim_entries_{}", i));
+            }
+            assert!(manager.tracked_keys().is_empty());
+
+            manager.lock("test_reclaim_entries".to_string()).await;
+            assert_eq!(manager.tracked_keys(), vec!["test_reclaim_entries".to_string()]);
+            manager.unlock(&"test_reclaim_entries".to_string());
+            assert!(manager.tracked_keys().is_empty());
+        });
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_cancel_waiter() {
+        async_std::task::block_on(async {
+            let locker = Locker::get_locker("test_cancel_waiter").await;
+            let waiter = async_std::task::spawn(async {
+                let _locker = Locker::get_locker("test_cancel_waiter").await;
+            });
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            waiter.cancel().await;
+            drop(locker);
+            let ret = Locker::get_locker_timeout("test_cancel_waiter", Duration::from_secs(1)).await;
+            assert!(ret.is_some());
+            drop(ret);
+
+            // the locker is handed to a waiter that is dropped before it is polled again
+            let locker = Locker::get_locker("test_cancel_waiter").await;
+            let mut waiter = Box::pin(Locker::get_locker("test_cancel_waiter"));
+            assert!(async_std::future::timeout(Duration::from_millis(100), &mut waiter).await.is_err());
+            drop(locker);
+            drop(waiter);
+            let ret = Locker::get_locker_timeout("test_cancel_waiter", Duration::from_secs(1)).await;
+            assert!(ret.is_some());
+        });
+    }
+
+    #[test]
+    fn test_unlock_unknown() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            manager.unlock(&"test_unlock_unknown".to_string());
+            manager.lock("test_unlock_unknown".to_string()).await;
+            manager.unlock(&"test_unlock_unknown".to_string());
+            manager.unlock(&"test_unlock_unknown".to_string());
+            #[cfg(debug_assertions)]
+            assert_eq!(manager.invalid_unlock_count(), 2);
+            assert!(manager.tracked_keys().is_empty());
+        });
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_independent_managers() {
+        async_std::task::block_on(async {
+            let manager1: LockerManager = LockerManager::new();
+            let manager2: LockerManager = LockerManager::new();
+            let _locker1 = manager1.get_locker("test_independent_managers").await;
+            let locker2 = manager2.get_locker_timeout("test_independent_managers", Duration::from_secs(1)).await;
+            assert!(locker2.is_some());
+            assert!(manager1.try_get_locker("test_independent_managers").is_none());
+            drop(locker2);
+            assert!(manager2.tracked_keys().is_empty());
+            assert_eq!(manager1.tracked_keys().len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_tuple_key() {
+        async_std::task::block_on(async {
+            let manager = LockerManager::<(u64, u64)>::new();
+            let locker = manager.get_locker((1, 2)).await;
+            assert!(manager.try_get_locker((1, 2)).is_none());
+            assert!(manager.try_get_locker((2, 1)).is_some());
+            drop(locker);
+            assert!(manager.try_get_locker((1, 2)).is_some());
+            assert!(manager.tracked_keys().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_reentrant() {
+        async_std::task::block_on(async {
+            let owner = LockOwner::new();
+            let outer = Locker::get_reentrant_locker("test_reentrant", owner).await;
+            let inner = async_std::future::timeout(Duration::from_secs(1),
+                                                   Locker::get_reentrant_locker("test_reentrant", owner)).await;
+            assert!(inner.is_ok());
+
+            let other = Locker::get_reentrant_locker("test_reentrant", LockOwner::new());
+            assert!(async_std::future::timeout(Duration::from_millis(100), other).await.is_err());
+            assert!(Locker::try_get_locker("test_reentrant").is_none());
+
+            drop(outer);
+            assert!(Locker::try_get_locker("test_reentrant").is_none());
+            drop(inner);
+            assert!(Locker::try_get_locker("test_reentrant").is_some());
+        });
+    }
+
+    #[test]
+    fn test_fifo_order() {
+        async_std::task::block_on(async {
+            let locker = Locker::get_locker("test_fifo_order").await;
+            let order = Arc::new(Mutex::new(Vec::new()));
+            let mut waiters = Vec::new();
+            for i in 0..5 {
+                let order = order.clone();
+                waiters.push(async_std::task::spawn(async move {
+                    let _locker = Locker::get_locker("test_fifo_order").await;
+                    order.lock().unwrap().push(i);
+                }));
+                async_std::task::sleep(Duration::from_millis(50)).await;
+            }
+            drop(locker);
+            for waiter in waiters {
+                waiter.await;
+            }
+            assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+        });
+    }
+
+    #[test]
+    fn test_locker_id() {
+        async_std::task::block_on(async {
+            let user_id = 10;
+            let locker = Locker::get_locker(format!("test_locker_id_{}", user_id)).await;
+            assert_eq!(locker.id(), "test_locker_id_10");
+
+            let guard = GuardObject::new(locker, user_id);
+            assert_eq!(guard.locker_id(), "test_locker_id_10");
+            assert_eq!(*guard, 10);
+        });
+    }
+
+    #[test]
+    fn test_read_write() {
+        async_std::task::block_on(async {
+            let reader1 = Locker::get_read_locker("test_read_write").await;
+            let reader2 = async_std::future::timeout(Duration::from_secs(1),
+                                                     Locker::get_read_locker("test_read_write")).await;
+            assert!(reader2.is_ok());
+            assert!(Locker::try_get_locker("test_read_write").is_none());
+
+            let writer = async_std::task::spawn(async {
+                let _writer = Locker::get_write_locker("test_read_write").await;
+                async_std::task::sleep(Duration::from_millis(200)).await;
+            });
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            // the queued writer keeps new readers out
+            let reader3 = Locker::get_read_locker("test_read_write");
+            assert!(async_std::future::timeout(Duration::from_millis(100), reader3).await.is_err());
+
+            drop(reader1);
+            drop(reader2);
+            let reader3 = Locker::get_read_locker("test_read_write");
+            assert!(async_std::future::timeout(Duration::from_millis(100), reader3).await.is_err());
+            writer.await;
+            let reader3 = Locker::get_read_locker("test_read_write");
+            assert!(async_std::future::timeout(Duration::from_millis(100), reader3).await.is_ok());
+            assert!(Locker::try_get_locker("test_read_write").is_some());
+        });
+    }
+
+    #[test]
+    fn test_get_lockers() {
+        async_std::task::block_on(async {
+            let mut tasks = Vec::new();
+            for ids in [["test_get_lockers_a", "test_get_lockers_b"], ["test_get_lockers_b", "test_get_lockers_a"]] {
+                tasks.push(async_std::task::spawn(async move {
+                    for _ in 0..100 {
+                        let lockers = Locker::get_lockers(ids.to_vec()).await;
+                        assert_eq!(lockers.len(), 2);
+                        assert_eq!(lockers[0].id(), "test_get_lockers_b");
+                        async_std::task::yield_now().await;
+                    }
+                }));
+            }
+            for task in tasks {
+                assert!(async_std::future::timeout(Duration::from_secs(10), task).await.is_ok());
+            }
+        });
+    }
+
+    #[test]
+    fn test_get_lockers_edge_cases() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            assert!(manager.get_lockers(Vec::new()).await.is_empty());
+
+            let lockers = manager.get_lockers(vec!["a".to_string(), "a".to_string()]).await;
+            assert_eq!(lockers.iter().map(|locker| locker.id().as_str()).collect::<Vec<_>>(), vec!["a"]);
+            drop(lockers);
+
+            let lockers = manager.get_lockers(vec!["b".to_string(), "a".to_string(), "b".to_string()]).await;
+            assert_eq!(lockers.iter().map(|locker| locker.id().as_str()).collect::<Vec<_>>(), vec!["b", "a"]);
+            drop(lockers);
+            assert!(manager.tracked_keys().is_empty());
+        });
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let locker = manager.get_locker("test_metrics").await;
+            let waiter = {
+                let manager = manager.clone();
+                async_std::task::spawn(async move {
+                    let _locker = manager.get_locker("test_metrics").await;
+                })
+            };
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            assert_eq!(manager.metrics().contended_acquisitions, 1);
+            assert_eq!(manager.metrics().max_pending_depth, 1);
+            drop(locker);
+            waiter.await;
+
+            let metrics = manager.metrics();
+            assert_eq!(metrics.acquisitions, 2);
+            assert_eq!(metrics.immediate_acquisitions, 1);
+            assert_eq!(metrics.contended_acquisitions, 1);
+        });
+    }
+
+    #[test]
+    fn test_debug() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let locker = manager.get_locker("test_debug").await;
+            assert_eq!(format!("{:?}", locker), "Locker { id: \"test_debug\" }");
+            assert_eq!(format!("{:?}", manager), "LockerManager { tracked: 1 }");
+            let guard = GuardObject::new(locker, ());
+            assert_eq!(format!("{:?}", guard), "GuardObject { locker_id: \"test_debug\", .. }");
+            drop(guard);
+
+            let reader = manager.get_read_locker("test_debug").await;
+            assert_eq!(format!("{:?}", reader), "ReadLocker { id: \"test_debug\" }");
+            drop(reader);
+            let writer = manager.get_write_locker("test_debug").await;
+            assert_eq!(format!("{:?}", writer), "WriteLocker { id: \"test_debug\" }");
+        });
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_guard_object_parts() {
+        async_std::task::block_on(async {
+            let guard = GuardObject::new(Locker::get_locker("test_guard_object_parts").await, vec![1]);
+            let (locker, obj) = guard.into_parts();
+            assert_eq!(locker.id(), "test_guard_object_parts");
+            assert!(Locker::try_get_locker("test_guard_object_parts").is_none());
+
+            let guard = GuardObject::new(locker, obj);
+            let mut obj = guard.unlock_keep();
+            let other = async_std::task::spawn(async {
+                Locker::get_locker_timeout("test_guard_object_parts", Duration::from_secs(1)).await.is_some()
+            });
+            assert!(other.await);
+            obj.push(2);
+            assert_eq!(obj, vec![1, 2]);
+        });
+    }
+
+    #[cfg(any(feature = "deadlock-detection", feature = "hold-warnings"))]
+    struct CaptureLogger;
+
+    #[cfg(any(feature = "deadlock-detection", feature = "hold-warnings"))]
+    lazy_static::lazy_static! {
+        static ref CAPTURED_LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    }
+
+    #[cfg(any(feature = "deadlock-detection", feature = "hold-warnings"))]
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                CAPTURED_LOGS.lock().unwrap().push(format!("{}", record.args()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[cfg(any(feature = "deadlock-detection", feature = "hold-warnings"))]
+    fn capture_logs() {
+        static LOGGER: CaptureLogger = CaptureLogger;
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Warn);
+        }
+    }
+
+    #[cfg(feature = "deadlock-detection")]
+    #[test]
+    fn test_deadlock_detection() {
+        capture_logs();
+        async_std::task::block_on(async {
+            let owner_a = LockOwner::new();
+            let owner_b = LockOwner::new();
+            let _a = Locker::get_locker_with_owner("test_deadlock_a", owner_a).await;
+            let _b = Locker::get_locker_with_owner("test_deadlock_b", owner_b).await;
+            let b_waits_a = Locker::get_locker_with_owner("test_deadlock_a", owner_b);
+            assert!(async_std::future::timeout(Duration::from_millis(100), b_waits_a).await.is_err());
+            assert!(!CAPTURED_LOGS.lock().unwrap().iter().any(|log| log.contains("test_deadlock_")));
+
+            let mut b_waits_a = Box::pin(Locker::get_locker_with_owner("test_deadlock_a", owner_b));
+            assert!(async_std::future::timeout(Duration::from_millis(100), &mut b_waits_a).await.is_err());
+            let a_waits_b = Locker::get_locker_with_owner("test_deadlock_b", owner_a);
+            assert!(async_std::future::timeout(Duration::from_millis(100), a_waits_b).await.is_err());
+            assert!(CAPTURED_LOGS.lock().unwrap().iter().any(|log| {
+                log.contains("deadlock detected") && log.contains("test_deadlock_a") && log.contains("test_deadlock_b")
+            }));
+        });
+    }
+
+    #[test]
+    fn test_move_locker_to_task() {
+        async_std::task::block_on(async {
+            let locker = Locker::get_locker("test_move_locker_to_task").await;
+            async_std::task::spawn(async move {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+                drop(locker);
+            }).await;
+            assert!(Locker::try_get_locker("test_move_locker_to_task").is_some());
+        });
+    }
+
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct CaptureSubscriber {
+        spans: Mutex<Vec<String>>,
+        events: Mutex<Vec<String>>,
+        seq: std::sync::atomic::AtomicU64,
+    }
+
+    #[cfg(feature = "tracing")]
+    struct FieldVisitor(String);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for FieldVisitor {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0 += &format!("{}={:?} ", field.name(), value);
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for CaptureSubscriber {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut visitor = FieldVisitor(format!("{} ", span.metadata().name()));
+            span.record(&mut visitor);
+            self.spans.lock().unwrap().push(visitor.0);
+            tracing::span::Id::from_u64(self.seq.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut visitor = FieldVisitor(String::new());
+            event.record(&mut visitor);
+            self.events.lock().unwrap().push(visitor.0);
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        let subscriber = Arc::new(CaptureSubscriber::default());
+        tracing::subscriber::with_default(subscriber.clone(), || {
+            async_std::task::block_on(async {
+                let _locker = Locker::get_locker("test_tracing").await;
+            });
+        });
+        let spans = subscriber.spans.lock().unwrap();
+        assert!(spans.iter().any(|span| span.starts_with("locker.acquire id=\"test_tracing\"")));
+        assert!(spans.iter().any(|span| span.starts_with("locker.held id=\"test_tracing\"")));
+        let events = subscriber.events.lock().unwrap();
+        assert!(events.iter().any(|event| event.contains("id=\"test_tracing\"") && event.contains("locker acquired")));
+    }
+
+    #[test]
+    fn test_introspection() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let id = "test_introspection".to_string();
+            assert!(!manager.is_locked(&id));
+            assert_eq!(manager.pending_count(&id), 0);
+
+            let locker = manager.get_locker(id.clone()).await;
+            let mut waiters = Vec::new();
+            for _ in 0..2 {
+                let manager = manager.clone();
+                waiters.push(async_std::task::spawn(async move {
+                    let _locker = manager.get_locker("test_introspection").await;
+                }));
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            assert!(manager.is_locked(&id));
+            assert_eq!(manager.pending_count(&id), 2);
+
+            drop(locker);
+            for waiter in waiters {
+                waiter.await;
+            }
+            assert!(!manager.is_locked(&id));
+            assert_eq!(manager.pending_count(&id), 0);
+        });
+    }
+
+    #[test]
+    fn test_max_waiters() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::builder().max_waiters(2).build();
+            let locker = manager.get_locker_checked("test_max_waiters").await.unwrap();
+            let mut waiters = Vec::new();
+            for _ in 0..2 {
+                let manager = manager.clone();
+                waiters.push(async_std::task::spawn(async move {
+                    manager.get_locker_checked("test_max_waiters").await.is_ok()
+                }));
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            let ret = manager.get_locker_checked("test_max_waiters").await;
+            assert_eq!(ret.unwrap_err(), LockerError::WouldQueueTooDeep);
+
+            drop(locker);
+            for waiter in waiters {
+                assert!(waiter.await);
+            }
+        });
+    }
+
+    #[test]
+    fn test_semaphore() {
+        async_std::task::block_on(async {
+            let running = Arc::new(AtomicUsize::new(0));
+            let max_running = Arc::new(AtomicUsize::new(0));
+            let mut tasks = Vec::new();
+            for _ in 0..4 {
+                let running = running.clone();
+                let max_running = max_running.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    let _permit = Locker::get_semaphore("test_semaphore", 3).await;
+                    let count = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(count, Ordering::SeqCst);
+                    async_std::task::sleep(Duration::from_millis(100)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }));
+            }
+            for task in tasks {
+                task.await;
+            }
+            assert_eq!(max_running.load(Ordering::SeqCst), 3);
+
+            // a held permit keeps writers out
+            let manager: LockerManager = LockerManager::new();
+            let permit = manager.get_semaphore("test_semaphore", 3).await;
+            assert!(manager.try_get_locker("test_semaphore").is_none());
+            drop(permit);
+            assert!(manager.try_get_locker("test_semaphore").is_some());
+            assert!(manager.tracked_keys().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_poison() {
+        let manager: LockerManager = LockerManager::new();
+        let holder = manager.clone();
+        let ret = std::thread::spawn(move || {
+            async_std::task::block_on(async {
+                let locker = holder.get_locker("test_poison").await;
+                locker.poison_on_panic(async {
+                    async_std::task::yield_now().await;
+                    panic!("panic while holding the locker");
+                }).await;
+            })
+        }).join();
+        assert!(ret.is_err());
+
+        async_std::task::block_on(async {
+            let locker = manager.get_locker_poisonable("test_poison").await.unwrap_err().into_inner();
+            assert_eq!(locker.id(), "test_poison");
+            drop(locker);
+            assert!(manager.is_poisoned(&"test_poison".to_string()));
+            manager.clear_poison(&"test_poison".to_string());
+            assert!(manager.get_locker_poisonable("test_poison").await.is_ok());
+            assert!(manager.tracked_keys().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_with_locker() {
+        async_std::task::block_on(async {
+            let counter = Arc::new(Mutex::new(0));
+            let mut tasks = Vec::new();
+            for _ in 0..10 {
+                let counter = counter.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    Locker::with_locker("test_with_locker", || async {
+                        let value = *counter.lock().unwrap();
+                        async_std::task::sleep(Duration::from_millis(10)).await;
+                        *counter.lock().unwrap() = value + 1;
+                        value
+                    }).await
+                }));
+            }
+            let mut values = Vec::new();
+            for task in tasks {
+                values.push(task.await);
+            }
+            values.sort();
+            assert_eq!(values, (0..10).collect::<Vec<_>>());
+            assert_eq!(*counter.lock().unwrap(), 10);
+        });
+    }
+
+    #[test]
+    fn test_get_locker_timed() {
+        async_std::task::block_on(async {
+            let (locker, wait) = Locker::get_locker_timed("test_get_locker_timed").await;
+            assert!(wait < Duration::from_millis(50));
+            let waiter = async_std::task::spawn(async {
+                Locker::get_locker_timed("test_get_locker_timed").await.1
+            });
+            async_std::task::sleep(Duration::from_millis(200)).await;
+            drop(locker);
+            assert!(waiter.await >= Duration::from_millis(200));
+        });
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_ttl() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let id = "test_ttl".to_string();
+            let stuck = manager.get_locker_with_ttl(id.clone(), Duration::from_millis(100)).await;
+            let locker = manager.get_locker_timeout(id.clone(), Duration::from_secs(1)).await;
+            assert!(locker.is_some());
+            // the expired locker doesn't release the one held now
+            drop(stuck);
+            assert!(manager.is_locked(&id));
+            #[cfg(debug_assertions)]
+            assert_eq!(manager.invalid_unlock_count(), 0);
+            drop(locker);
+            assert!(manager.tracked_keys().is_empty());
+
+            // a locker released before its ttl isn't released again by the expiry
+            let short = manager.get_locker_with_ttl(id.clone(), Duration::from_millis(100)).await;
+            drop(short);
+            let _locker = manager.get_locker(id.clone()).await;
+            async_std::task::sleep(Duration::from_millis(200)).await;
+            assert!(manager.is_locked(&id));
+        });
+    }
+
+    #[test]
+    fn test_shutdown() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let locker = manager.get_locker_checked("test_shutdown").await.unwrap();
+            let waiter = {
+                let manager = manager.clone();
+                async_std::task::spawn(async move {
+                    manager.get_locker_checked("test_shutdown").await.is_ok()
+                })
+            };
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            manager.begin_shutdown();
+            let ret = manager.get_locker_checked("test_shutdown_other").await;
+            assert_eq!(ret.unwrap_err(), LockerError::ShuttingDown);
+            assert!(manager.try_get_locker("test_shutdown_other").is_none());
+            let id = "test_shutdown_other".to_string();
+            assert_eq!(manager.lock_checked(id.clone()).await.unwrap_err(), LockerError::ShuttingDown);
+            assert_eq!(manager.try_lock_checked(&id).unwrap_err(), LockerError::ShuttingDown);
+            assert_eq!(manager.get_read_locker_checked(id.clone()).await.unwrap_err(), LockerError::ShuttingDown);
+            assert_eq!(manager.get_upgradeable_locker_checked(id.clone()).await.unwrap_err(), LockerError::ShuttingDown);
+            assert_eq!(manager.get_semaphore_checked(id.clone(), 2).await.unwrap_err(), LockerError::ShuttingDown);
+            assert_eq!(manager.get_locker_blocking_checked(id.clone()).unwrap_err(), LockerError::ShuttingDown);
+            assert_eq!(manager.lock_handle_checked(id.clone()).unwrap_err(), LockerError::ShuttingDown);
+            assert!(!manager.is_locked(&id));
+
+            let drained = {
+                let manager = manager.clone();
+                async_std::task::spawn(async move {
+                    manager.await_drained().await;
+                })
+            };
+            drop(locker);
+            assert!(waiter.await);
+            async_std::future::timeout(Duration::from_secs(1), drained).await.unwrap();
+            assert!(manager.tracked_keys().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_panic_after_shutdown() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::builder().panic_after_shutdown().build();
+            let upgradeable = manager.get_upgradeable_locker("test_panic_after_shutdown").await;
+            manager.begin_shutdown();
+            let ret = std::panic::catch_unwind(AssertUnwindSafe(|| manager.lock_acquire("test_panic_after_shutdown_other")));
+            assert!(ret.is_err());
+            let ret = std::panic::catch_unwind(AssertUnwindSafe(|| manager.lock_blocking("test_panic_after_shutdown_other".to_string())));
+            assert!(ret.is_err());
+            assert!(!manager.is_locked(&"test_panic_after_shutdown_other".to_string()));
+            // the checked variants still report the refusal, and holders can still upgrade
+            let ret = manager.get_locker_checked("test_panic_after_shutdown_other").await;
+            assert_eq!(ret.unwrap_err(), LockerError::ShuttingDown);
+            drop(upgradeable.upgrade().await);
+            async_std::future::timeout(Duration::from_secs(1), manager.await_drained()).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_key_handle() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let id = "test_key_handle".to_string();
+            let key = manager.key(id.clone());
+            assert_eq!(key.id(), &id);
+            key.lock().await;
+            assert!(key.is_locked());
+            assert!(manager.is_locked(&id));
+            assert!(!key.try_lock());
+            assert!(!manager.try_lock(&id));
+            // the handle and the direct methods work on the same locker
+            manager.unlock(&id);
+            assert!(!key.is_locked());
+            assert!(key.try_lock());
+            assert!(manager.is_locked(&id));
+            key.unlock();
+            assert!(manager.tracked_keys().is_empty());
+
+            let locker = key.get_locker().await;
+            assert!(manager.try_get_locker(id.clone()).is_none());
+            drop(locker);
+            assert!(!key.is_locked());
+            assert_eq!(format!("{:?}", key), "KeyHandle { id: \"test_key_handle\" }");
+        });
+    }
+
+    #[test]
+    fn test_custom_hasher() {
+        async_std::task::block_on(async {
+            let manager: LockerManager<String, BuildHasherDefault<DefaultHasher>> = LockerManager::new();
+            let locker = manager.get_locker("test_custom_hasher").await;
+            assert!(manager.is_locked(&"test_custom_hasher".to_string()));
+            assert!(manager.try_get_locker("test_custom_hasher").is_none());
+            drop(locker);
+            assert!(manager.try_get_locker("test_custom_hasher").is_some());
+
+            let manager: LockerManager<u64, BuildHasherDefault<DefaultHasher>> = LockerManager::builder()
+                .hasher(BuildHasherDefault::default())
+                .max_waiters(1)
+                .build();
+            let _locker = manager.get_locker(1u64).await;
+            assert!(manager.try_get_locker(2u64).is_some());
+            assert_eq!(manager.tracked_keys(), vec![1]);
+        });
+    }
+
+    #[test]
+    fn test_lock_blocking() {
+        let manager: LockerManager = LockerManager::new();
+        let id = "test_lock_blocking".to_string();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let locker = async_std::task::block_on(manager.get_locker(id.clone()));
+
+        let blocking = {
+            let manager = manager.clone();
+            let order = order.clone();
+            std::thread::spawn(move || {
+                let _locker = manager.get_locker_blocking("test_lock_blocking");
+                order.lock().unwrap().push("blocking");
+                std::thread::sleep(Duration::from_millis(100));
+            })
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        let waiter = {
+            let manager = manager.clone();
+            let order = order.clone();
+            async_std::task::spawn(async move {
+                let _locker = manager.get_locker("test_lock_blocking").await;
+                order.lock().unwrap().push("async");
+            })
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(manager.pending_count(&id), 2);
+        drop(locker);
+        blocking.join().unwrap();
+        async_std::task::block_on(waiter);
+        assert_eq!(*order.lock().unwrap(), vec!["blocking", "async"]);
+
+        // a free locker is taken without blocking
+        manager.lock_blocking(id.clone());
+        assert!(manager.is_locked(&id));
+        manager.unlock(&id);
+        assert!(manager.tracked_keys().is_empty());
+    }
+
+    #[test]
+    fn test_upgradeable_locker() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let upgradeable = manager.get_upgradeable_locker("test_upgradeable_locker").await;
+            // plain readers still get in, a second upgradeable reader doesn't
+            let reader = manager.get_read_locker("test_upgradeable_locker").await;
+            let second = {
+                let manager = manager.clone();
+                async_std::task::spawn(async move {
+                    manager.get_upgradeable_locker("test_upgradeable_locker").await
+                })
+            };
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            assert_eq!(manager.pending_count(&"test_upgradeable_locker".to_string()), 1);
+
+            let upgraded = Arc::new(AtomicUsize::new(0));
+            let upgrading = {
+                let upgraded = upgraded.clone();
+                async_std::task::spawn(async move {
+                    let writer = upgradeable.upgrade().await;
+                    upgraded.store(1, Ordering::SeqCst);
+                    async_std::task::sleep(Duration::from_millis(100)).await;
+                    drop(writer);
+                })
+            };
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            assert_eq!(upgraded.load(Ordering::SeqCst), 0);
+            drop(reader);
+            upgrading.await;
+            assert_eq!(upgraded.load(Ordering::SeqCst), 1);
+            drop(second.await);
+
+            // read, check and write stay atomic for every upgrading task
+            let counter = Arc::new(Mutex::new(0));
+            let mut tasks = Vec::new();
+            for _ in 0..2 {
+                let manager = manager.clone();
+                let counter = counter.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    let locker = manager.get_upgradeable_locker("test_upgradeable_locker").await;
+                    let value = *counter.lock().unwrap();
+                    async_std::task::sleep(Duration::from_millis(50)).await;
+                    let _writer = locker.upgrade().await;
+                    *counter.lock().unwrap() = value + 1;
+                }));
+            }
+            for task in tasks {
+                task.await;
+            }
+            assert_eq!(*counter.lock().unwrap(), 2);
+            assert!(manager.tracked_keys().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_upgrade_blocks_readers_after_cancelled_writer() {
+        async_std::task::block_on(async {
+            let id = "test_upgrade_blocks_readers_after_cancelled_writer".to_string();
+            let manager: LockerManager = LockerManager::new();
+            let upgradeable = manager.get_upgradeable_locker(id.clone()).await;
+            let reader = manager.get_read_locker(id.clone()).await;
+            let upgrading = async_std::task::spawn(async move { upgradeable.upgrade().await });
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            let mut writer = Box::pin(manager.get_locker(id.clone()));
+            assert!(async_std::future::timeout(Duration::from_millis(50), &mut writer).await.is_err());
+            let late_reader = {
+                let manager = manager.clone();
+                let id = id.clone();
+                async_std::task::spawn(async move { manager.get_read_locker(id).await })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(manager.pending_count(&id), 3);
+
+            // the cancelled writer doesn't let the late reader in ahead of the upgrade
+            drop(writer);
+            assert_eq!(manager.pending_count(&id), 2);
+            drop(reader);
+            let upgraded = async_std::future::timeout(Duration::from_secs(1), upgrading).await.unwrap();
+            assert_eq!(manager.pending_count(&id), 1);
+            drop(upgraded);
+            drop(async_std::future::timeout(Duration::from_secs(1), late_reader).await.unwrap());
+            assert!(manager.tracked_keys().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_observer() {
+        struct CountingObserver {
+            manager: LockerManager,
+            acquired: AtomicUsize,
+            released: AtomicUsize,
+            max_waited: Mutex<Duration>,
+        }
+
+        impl LockerObserver<String> for CountingObserver {
+            fn on_acquire(&self, locker_id: &String, waited: Duration) {
+                // calling back into the manager doesn't deadlock
+                assert!(self.manager.is_locked(locker_id));
+                self.acquired.fetch_add(1, Ordering::SeqCst);
+                let mut max_waited = self.max_waited.lock().unwrap();
+                *max_waited = (*max_waited).max(waited);
+            }
+
+            fn on_release(&self, _locker_id: &String) {
+                self.released.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let observer = Arc::new(CountingObserver {
+                manager: manager.clone(),
+                acquired: AtomicUsize::new(0),
+                released: AtomicUsize::new(0),
+                max_waited: Mutex::new(Duration::ZERO),
+            });
+            manager.set_observer(observer.clone());
+
+            let locker = manager.get_locker("test_observer").await;
+            let waiter = {
+                let manager = manager.clone();
+                async_std::task::spawn(async move {
+                    drop(manager.get_locker("test_observer").await);
+                })
+            };
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            drop(locker);
+            waiter.await;
+            drop(manager.try_get_locker("test_observer"));
+            drop(manager.get_read_locker("test_observer").await);
+
+            // a waiter dropped after the locker was handed to it, and an unpolled handle that took the free
+            // locker, pass it on without being reported
+            let locker = manager.get_locker("test_observer").await;
+            let mut waiter = Box::pin(manager.get_locker("test_observer"));
+            assert!(async_std::future::timeout(Duration::from_millis(50), &mut waiter).await.is_err());
+            drop(locker);
+            drop(waiter);
+            let (future, _ticket) = manager.lock_handle("test_observer");
+            drop(future);
+            assert!(!manager.is_locked(&"test_observer".to_string()));
+
+            assert_eq!(observer.acquired.load(Ordering::SeqCst), 5);
+            assert_eq!(observer.released.load(Ordering::SeqCst), 5);
+            assert!(*observer.max_waited.lock().unwrap() >= Duration::from_millis(100));
+        });
+    }
+
+    #[test]
+    fn test_was_contended() {
+        async_std::task::block_on(async {
+            let locker = Locker::get_locker("test_was_contended").await;
+            assert!(!locker.was_contended());
+            let waiter = async_std::task::spawn(async {
+                Locker::get_locker("test_was_contended").await.was_contended()
+            });
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            drop(locker);
+            assert!(waiter.await);
+            assert!(!Locker::try_get_locker("test_was_contended").unwrap().was_contended());
+        });
+    }
+
+    #[test]
+    fn test_priority() {
+        async_std::task::block_on(async {
+            let order = Arc::new(Mutex::new(Vec::new()));
+            let locker = Locker::get_locker("test_priority").await;
+            let mut tasks = Vec::new();
+            for (name, priority) in [("low", Priority::Low), ("normal", Priority::Normal), ("high", Priority::High)] {
+                let order = order.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    let _locker = Locker::get_locker_prio("test_priority", priority).await;
+                    order.lock().unwrap().push(name);
+                }));
+                async_std::task::sleep(Duration::from_millis(50)).await;
+            }
+            drop(locker);
+            for task in tasks {
+                task.await;
+            }
+            assert_eq!(*order.lock().unwrap(), vec!["high", "normal", "low"]);
+
+            // a waiter that was overtaken once already isn't overtaken again
+            let manager = LockerManager::<String>::builder().max_overtakes(1).build();
+            let order = Arc::new(Mutex::new(Vec::new()));
+            let locker = manager.get_locker("test_priority").await;
+            let mut tasks = Vec::new();
+            for (name, priority) in [("low", Priority::Low), ("high1", Priority::High), ("high2", Priority::High)] {
+                let manager = manager.clone();
+                let order = order.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    let _locker = manager.get_locker_prio("test_priority", priority).await;
+                    order.lock().unwrap().push(name);
+                }));
+                async_std::task::sleep(Duration::from_millis(50)).await;
+            }
+            drop(locker);
+            for task in tasks {
+                task.await;
+            }
+            assert_eq!(*order.lock().unwrap(), vec!["high1", "low", "high2"]);
+        });
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_claim() {
+        async_std::task::block_on(async {
+            let claim = Locker::claim("test_claim", Duration::from_millis(200)).await;
+            drop(claim);
+            // a retry right after the release still finds the id claimed
+            assert!(Locker::try_get_locker("test_claim").is_none());
+            let (retry, waited) = Locker::get_locker_timed("test_claim").await;
+            assert!(waited >= Duration::from_millis(150));
+            drop(retry);
+            assert!(Locker::try_get_locker("test_claim").is_some());
+        });
+    }
+
+    #[test]
+    fn test_held_longer_than() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let _stuck = manager.get_locker("test_held_longer_than_stuck").await;
+            let _reader = manager.get_read_locker("test_held_longer_than_reader").await;
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            let _fresh = manager.get_locker("test_held_longer_than_fresh").await;
+            let held = manager.held_longer_than(Duration::from_millis(50));
+            assert_eq!(held.len(), 1);
+            assert_eq!(held[0].0, "test_held_longer_than_stuck");
+            assert!(held[0].1 >= Duration::from_millis(100));
+        });
+    }
+
+    // parking_lot mutexes don't poison
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_poisoned_map_mutex() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let state = manager.state.clone();
+            let _ = std::thread::spawn(move || {
+                let _locker_map = state.locker_map.lock().unwrap();
+                panic!("panic while holding the locker map");
+            }).join();
+            assert!(manager.state.locker_map.is_poisoned());
+            let locker = manager.get_locker("test_poisoned_map_mutex").await;
+            assert!(manager.try_get_locker("test_poisoned_map_mutex").is_none());
+            drop(locker);
+            assert!(manager.try_get_locker("test_poisoned_map_mutex").is_some());
+        });
+    }
+
+    #[test]
+    fn test_named_lock_macro() {
+        async_std::task::block_on(async {
+            let counter = Arc::new(AtomicUsize::new(0));
+            let mut tasks = Vec::new();
+            for i in 0..4 {
+                let counter = counter.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    if i % 2 == 0 {
+                        crate::named_lock!("test_named_lock_macro");
+                        let value = counter.load(Ordering::SeqCst);
+                        async_std::task::sleep(Duration::from_millis(20)).await;
+                        counter.store(value + 1, Ordering::SeqCst);
+                    } else {
+                        let value = crate::named_lock!("test_named_lock_macro" => {
+                            let value = counter.load(Ordering::SeqCst);
+                            async_std::task::sleep(Duration::from_millis(20)).await;
+                            counter.store(value + 1, Ordering::SeqCst);
+                            value + 1
+                        });
+                        assert!(value > 0);
+                    }
+                }));
+            }
+            for task in tasks {
+                task.await;
+            }
+            assert_eq!(counter.load(Ordering::SeqCst), 4);
+            assert!(Locker::try_get_locker("test_named_lock_macro").is_some());
+        });
+    }
+
+    #[test]
+    fn test_namespace() {
+        async_std::task::block_on(async {
+            let a = Locker::with_namespace("test_namespace_a");
+            let b = Locker::with_namespace("test_namespace_b:");
+            assert_eq!(a.id("x"), "test_namespace_a:x");
+            assert_eq!(b.id("x"), "test_namespace_b:x");
+            assert_eq!(a.namespace("sub:").id("x"), "test_namespace_a:sub:x");
+
+            let locker = a.get_locker("x").await;
+            assert_eq!(locker.id(), "test_namespace_a:x");
+            assert!(b.try_get_locker("x").is_some());
+            assert!(a.try_get_locker("x").is_none());
+            assert!(Locker::try_get_locker("x").is_some());
+
+            // `LockId` constants name namespaces and locker sets as well
+            const USERS: LockId = LockId::new("test_namespace_users");
+            assert_eq!(Locker::with_namespace(USERS).namespace(LockId::new("admins")).id("x"), "test_namespace_users:admins:x");
+            let lockers = Locker::get_lockers(vec![LockId::new("test_namespace_b"), USERS]).await;
+            assert_eq!(lockers.len(), 2);
+        });
+    }
+
+    crate::locker_id!(struct UserId;);
+    crate::locker_id!(struct OrderId;);
+
+    #[test]
+    fn test_locker_id_macro() {
+        async_std::task::block_on(async {
+            const ADMIN: UserId = UserId::new("admin");
+            let users: LockerManager<UserId> = LockerManager::new();
+            let orders: LockerManager<OrderId> = LockerManager::new();
+            let locker = users.get_locker(ADMIN).await;
+            assert_eq!(locker.id().as_str(), "admin");
+            assert!(users.try_get_locker(UserId::from("admin".to_string())).is_none());
+            // `users.try_get_locker(OrderId::new("admin"))` doesn't compile, the domains are different types
+            let order = orders.try_get_locker(OrderId::new("admin")).unwrap();
+            assert_eq!(order.id().as_str(), "admin");
+            assert_ne!(std::any::TypeId::of::<UserId>(), std::any::TypeId::of::<OrderId>());
+            assert_eq!(std::mem::size_of::<UserId>(), std::mem::size_of::<std::borrow::Cow<'static, str>>());
+        });
+    }
+
+    #[test]
+    fn test_lock_id() {
+        async_std::task::block_on(async {
+            const CONFIG: LockId = crate::locker_id!("test_lock_id");
+            let locker = Locker::get_locker(CONFIG).await;
+            assert_eq!(locker.id(), "test_lock_id");
+            // plain strings still name the same global locker
+            assert!(Locker::try_get_locker("test_lock_id").is_none());
+            assert!(Locker::try_get_locker(String::from("test_lock_id")).is_none());
+            drop(locker);
+            assert!(Locker::try_get_locker(LockId::from("test_lock_id")).is_some());
+            // `Locker::get_locker(UserId::new("test_lock_id"))` doesn't compile, domain ids aren't global ones
+            assert_eq!(std::mem::size_of::<LockId>(), std::mem::size_of::<std::borrow::Cow<'static, str>>());
+        });
+    }
+
+    #[test]
+    fn test_wake_counts() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let resumed = Arc::new(AtomicUsize::new(0));
+
+            // one permit waiter resumes per freed permit
+            let permit1 = manager.get_semaphore("test_wake_counts", 2).await;
+            let permit2 = manager.get_semaphore("test_wake_counts", 2).await;
+            let mut tasks = Vec::new();
+            for _ in 0..4 {
+                let manager = manager.clone();
+                let resumed = resumed.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    let _permit = manager.get_semaphore("test_wake_counts", 2).await;
+                    resumed.fetch_add(1, Ordering::SeqCst);
+                    async_std::task::sleep(Duration::from_millis(300)).await;
+                }));
+            }
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(resumed.load(Ordering::SeqCst), 0);
+            drop(permit1);
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(resumed.load(Ordering::SeqCst), 1);
+            drop(permit2);
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(resumed.load(Ordering::SeqCst), 2);
+            for task in tasks {
+                task.await;
+            }
+
+            // the readers queued behind a writer wait for the writer queued after them, then resume together
+            assert_eq!(wake_readers(&manager, &resumed).await, (0, 3));
+            let manager: LockerManager = LockerManager::builder().phase_fair().build();
+            // phase fair, they go before the next writer
+            assert_eq!(wake_readers(&manager, &resumed).await, (3, 1));
+        });
+    }
+
+    // Releases a writer that three readers and then another writer queued behind. Returns how many readers
+    // resumed and how many waiters were left queued right after the release.
+    async fn wake_readers(manager: &LockerManager, resumed: &Arc<AtomicUsize>) -> (usize, usize) {
+        resumed.store(0, Ordering::SeqCst);
+        let writer = manager.get_locker("test_wake_counts").await;
+        let mut tasks = Vec::new();
+        for _ in 0..3 {
+            let manager = manager.clone();
+            let resumed = resumed.clone();
+            tasks.push(async_std::task::spawn(async move {
+                let _reader = manager.get_read_locker("test_wake_counts").await;
+                resumed.fetch_add(1, Ordering::SeqCst);
+                async_std::task::sleep(Duration::from_millis(200)).await;
+            }));
+        }
+        async_std::task::sleep(Duration::from_millis(50)).await;
+        let next_writer = {
+            let manager = manager.clone();
+            async_std::task::spawn(async move {
+                let _writer = manager.get_locker("test_wake_counts").await;
+                async_std::task::sleep(Duration::from_millis(200)).await;
+            })
+        };
+        async_std::task::sleep(Duration::from_millis(50)).await;
+        assert_eq!(manager.pending_count(&"test_wake_counts".to_string()), 4);
+        drop(writer);
+        async_std::task::sleep(Duration::from_millis(50)).await;
+        let woken = (resumed.load(Ordering::SeqCst), manager.pending_count(&"test_wake_counts".to_string()));
+        for task in tasks {
+            task.await;
+        }
+        next_writer.await;
+        assert_eq!(resumed.load(Ordering::SeqCst), 3);
+        woken
+    }
+
+    #[test]
+    fn test_lock_handle() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let id = "test_lock_handle".to_string();
+            let holder = manager.get_locker(id.clone()).await;
+            let (first, first_ticket) = manager.lock_handle(id.clone());
+            let (second, second_ticket) = manager.lock_handle(id.clone());
+            let (third, third_ticket) = manager.lock_handle(id.clone());
+            assert_eq!(first_ticket.position(), Some(0));
+            assert_eq!(second_ticket.position(), Some(1));
+            assert_eq!(third_ticket.position(), Some(2));
+
+            assert!(second_ticket.cancel());
+            assert!(!second_ticket.cancel());
+            assert!(second.await.is_none());
+            assert_eq!(second_ticket.position(), None);
+            assert_eq!(third_ticket.position(), Some(1));
+            assert_eq!(manager.pending_count(&id), 2);
+
+            drop(holder);
+            let locker = first.await.unwrap();
+            assert!(locker.was_contended());
+            assert_eq!(first_ticket.position(), None);
+            assert!(!first_ticket.cancel());
+            assert_eq!(third_ticket.position(), Some(0));
+            drop(locker);
+            drop(third.await.unwrap());
+
+            // a free locker is taken right away, an unpolled future that drops gives it back
+            let (future, ticket) = manager.lock_handle(id.clone());
+            assert_eq!(ticket.position(), None);
+            assert!(manager.is_locked(&id));
+            drop(future);
+            assert!(manager.tracked_keys().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_release_hook() {
+        async_std::task::block_on(async {
+            let order = Arc::new(Mutex::new(Vec::new()));
+            let mut locker = Locker::get_locker("test_release_hook").await;
+            let hook_order = order.clone();
+            locker.set_release_hook(move || async move {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+                hook_order.lock().unwrap().push("hook");
+            });
+            let next = {
+                let order = order.clone();
+                async_std::task::spawn(async move {
+                    let _locker = Locker::get_locker("test_release_hook").await;
+                    order.lock().unwrap().push("next");
+                })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            locker.release().await;
+            next.await;
+            assert_eq!(*order.lock().unwrap(), vec!["hook", "next"]);
+
+            // dropping the locker skips the hook
+            let mut locker = Locker::get_locker("test_release_hook").await;
+            let hook_order = order.clone();
+            locker.set_release_hook(move || async move {
+                hook_order.lock().unwrap().push("dropped");
+            });
+            drop(locker);
+            assert!(Locker::try_get_locker("test_release_hook").is_some());
+            assert_eq!(order.lock().unwrap().len(), 2);
+        });
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_get_locker_spin() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let id = "test_get_locker_spin".to_string();
+            let locker = manager.get_locker(id.clone()).await;
+            let start = std::time::Instant::now();
+            assert!(manager.get_locker_spin(id.clone(), 3, Duration::from_millis(20)).await.is_none());
+            // two sleeps between three attempts, 20ms and 40ms
+            assert!(start.elapsed() >= Duration::from_millis(60));
+            // spinning doesn't queue
+            assert_eq!(manager.pending_count(&id), 0);
+
+            async_std::task::spawn(async move {
+                async_std::task::sleep(Duration::from_millis(50)).await;
+                drop(locker);
+            });
+            let locker = manager.get_locker_spin(id.clone(), 10, Duration::from_millis(10)).await.unwrap();
+            assert!(locker.was_contended());
+        });
+    }
+
+    #[cfg(feature = "hold-warnings")]
+    #[test]
+    fn test_hold_warning() {
+        capture_logs();
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::builder().warn_after(Duration::from_millis(50)).build();
+            drop(manager.get_locker("test_hold_warning_short").await);
+            let locker = manager.get_locker("test_hold_warning_long").await;
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            drop(locker);
+            let logs = CAPTURED_LOGS.lock().unwrap();
+            assert!(logs.iter().any(|log| log.contains("\"test_hold_warning_long\" held for")));
+            assert!(!logs.iter().any(|log| log.contains("test_hold_warning_short")));
+        });
+    }
+
+    #[test]
+    fn test_guard_object_acquire() {
+        async_std::task::block_on(async {
+            let counter = Arc::new(AtomicUsize::new(0));
+            let mut tasks = Vec::new();
+            for _ in 0..3 {
+                let counter = counter.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    let guard = GuardObject::acquire("test_guard_object_acquire", counter).await;
+                    let value = guard.load(Ordering::SeqCst);
+                    async_std::task::sleep(Duration::from_millis(20)).await;
+                    guard.store(value + 1, Ordering::SeqCst);
+                }));
+            }
+            for task in tasks {
+                task.await;
+            }
+            assert_eq!(counter.load(Ordering::SeqCst), 3);
+            let guard = GuardObject::acquire("test_guard_object_acquire", vec![1]).await;
+            assert_eq!(guard.locker_id(), "test_guard_object_acquire");
+            assert_eq!(*guard, vec![1]);
+        });
+    }
+
+    #[test]
+    fn test_tracked_count() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            assert_eq!(manager.tracked_count(), 0);
+            let a = manager.get_locker("test_tracked_count_a").await;
+            let b = manager.get_read_locker("test_tracked_count_b").await;
+            assert_eq!(manager.tracked_count(), 2);
+            // a waiter on a held id doesn't add an entry
+            let waiter = {
+                let manager = manager.clone();
+                async_std::task::spawn(async move { manager.get_locker("test_tracked_count_a").await; })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(manager.pending_count(&"test_tracked_count_a".to_string()), 1);
+            assert_eq!(manager.tracked_count(), 2);
+            drop(b);
+            assert_eq!(manager.tracked_count(), 1);
+            drop(a);
+            waiter.await;
+            assert_eq!(manager.tracked_count(), 0);
+        });
+    }
+
+    #[cfg(feature = "parking_lot")]
+    #[test]
+    fn test_parking_lot() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let locker = manager.get_locker("test_parking_lot").await;
+            let waiter = {
+                let manager = manager.clone();
+                async_std::task::spawn(async move { manager.get_locker("test_parking_lot").await.was_contended() })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            assert!(manager.try_get_locker("test_parking_lot").is_none());
+            drop(locker);
+            assert!(waiter.await);
+            assert_eq!(manager.tracked_count(), 0);
+        });
+    }
+
+    #[test]
+    fn test_lock_acquire() {
+        struct Pending {
+            acquire: LockAcquire,
+        }
+        async_std::task::block_on(async {
+            let locker = Locker::get_locker("test_lock_acquire").await;
+            let mut pending = Pending {
+                acquire: Locker::lock_acquire("test_lock_acquire"),
+            };
+            let mut polls = 0;
+            let waiting = std::future::poll_fn(|cx| {
+                polls += 1;
+                std::future::Future::poll(std::pin::Pin::new(&mut pending.acquire), cx).map(|_| ())
+            });
+            assert!(async_std::future::timeout(Duration::from_millis(50), waiting).await.is_err());
+            assert!(polls > 0);
+            drop(locker);
+            let locker = pending.acquire.await;
+            assert_eq!(locker.id(), "test_lock_acquire");
+            assert!(locker.was_contended());
+        });
+    }
+
+    #[test]
+    fn test_self_relock() {
+        async_std::task::block_on(async {
+            let owner = LockOwner::new();
+            let locker = Locker::get_locker_with_owner_checked("test_self_relock", owner).await.unwrap();
+            let relock = Locker::get_locker_with_owner_checked("test_self_relock", owner).await;
+            assert_eq!(relock.unwrap_err(), LockerError::WouldDeadlock);
+            assert_eq!(LockerError::WouldDeadlock.to_string(), "the owner already holds the locker");
+
+            // another owner just waits
+            let other = Locker::get_locker_with_owner_checked("test_self_relock", LockOwner::new());
+            assert!(async_std::future::timeout(Duration::from_millis(50), other).await.is_err());
+            drop(locker);
+            assert!(Locker::get_locker_with_owner_checked("test_self_relock", owner).await.is_ok());
+        });
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_get_or_else() {
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let id = "test_get_or_else".to_string();
+            let locker = manager.get_locker(id.clone()).await;
+            let fallback = manager.get_or_else(id.clone(), Duration::from_millis(50), || {
+                assert_eq!(manager.pending_count(&id), 0);
+                "cached"
+            }).await;
+            assert_eq!(fallback.unwrap_err(), "cached");
+            drop(locker);
+            assert!(manager.get_or_else(id.clone(), Duration::from_millis(50), || "cached").await.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_auto_traits() {
+        assert_send_sync::<LockerManager>();
+        assert_send_sync::<Locker>();
+        assert_send_sync::<crate::ReadLocker>();
+        assert_send_sync::<crate::WriteLocker>();
+        assert_send_sync::<crate::UpgradeableLocker>();
+        assert_send_sync::<crate::SemaphorePermit>();
+        assert_send_sync::<GuardObject<Vec<u8>>>();
+        assert_send_sync::<LockAcquire>();
+        assert_send_sync::<crate::AcquireFuture>();
+        assert_send_sync::<crate::WaitTicket>();
+        assert_send_sync::<Locker<u64, BuildHasherDefault<DefaultHasher>>>();
+        // the async fns can be spawned on multi-threaded runtimes
+        assert_send(&Locker::get_locker("test_auto_traits"));
+        assert_send(&Locker::get_read_locker("test_auto_traits"));
+        #[cfg(any(feature = "async-std", feature = "tokio"))]
+        assert_send(&Locker::get_locker_timeout("test_auto_traits", Duration::from_millis(10)));
+        assert_send(&Locker::get_semaphore("test_auto_traits", 1));
+    }
+
+    #[test]
+    fn test_lock_set() {
+        struct Recorded {
+            name: &'static str,
+            drops: Arc<Mutex<Vec<&'static str>>>,
+        }
+        impl Drop for Recorded {
+            fn drop(&mut self) {
+                self.drops.lock().unwrap().push(self.name);
+            }
+        }
+        async_std::task::block_on(async {
+            let manager: LockerManager = LockerManager::new();
+            let drops = Arc::new(Mutex::new(Vec::new()));
+            let objs = ["test_lock_set_b", "test_lock_set_c", "test_lock_set_a"].into_iter().map(|name| {
+                (name.to_string(), Recorded { name, drops: drops.clone() })
+            }).collect();
+            let mut set = manager.lock_many(objs).await.unwrap();
+            assert_eq!(set.len(), 3);
+            assert_eq!(format!("{:?}", set), "[\"test_lock_set_a\", \"test_lock_set_b\", \"test_lock_set_c\"]");
+            assert!(set.iter_mut().all(|guard| guard.name.starts_with("test_lock_set_")));
+            drop(set);
+            assert_eq!(*drops.lock().unwrap(), vec!["test_lock_set_c", "test_lock_set_b", "test_lock_set_a"]);
+            assert!(manager.tracked_keys().is_empty());
+
+            // a duplicate id is refused without locking the other ids
+            let objs = vec![("test_lock_set_a".to_string(), 1), ("test_lock_set_b".to_string(), 2), ("test_lock_set_a".to_string(), 3)];
+            assert_eq!(manager.lock_many(objs).await.unwrap_err(), LockerError::DuplicateId);
+            assert!(manager.tracked_keys().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_try_or_wait() {
+        async_std::task::block_on(async {
+            let locker = match Locker::try_or_wait("test_try_or_wait") {
+                TryOrWait::Locked(locker) => locker,
+                TryOrWait::Wait(_) => panic!("the locker is free"),
+            };
+            assert!(!locker.was_contended());
+            let wait = match Locker::try_or_wait("test_try_or_wait") {
+                TryOrWait::Locked(_) => panic!("the locker is held"),
+                TryOrWait::Wait(wait) => wait,
+            };
+            // released before the wait is first polled, the queued request still gets it
+            drop(locker);
+            let locker = async_std::future::timeout(Duration::from_secs(1), wait).await.unwrap();
+            assert!(locker.was_contended());
         });
     }
 }
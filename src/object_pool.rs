@@ -0,0 +1,1266 @@
+use std::any::Any;
+use std::cmp::Ordering;
+use std::sync::{Arc, Weak};
+use std::collections::{HashMap, VecDeque};
+use std::ops::{Deref, DerefMut};
+use notify_future::NotifyFuture;
+use crate::sync::{LockRecover, Mutex};
+use crate::PoolError;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use std::time::Duration;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use crate::timer;
+
+// Which waiter a released object goes to. `Fifo` serves waiters in the order they came, so none starves,
+// `Lifo` the latest one, whose caches are likely still warm, at the risk of starving early ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum WaitPolicy {
+    #[default]
+    Fifo,
+    Lifo,
+}
+
+// Where the object of `get_entry` came from: `Stored` objects sat in the pool, possibly for a long time,
+// a `Handoff` one was handed over by the guard that released it while the caller waited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provenance {
+    Stored,
+    Handoff,
+}
+
+// What `ObjectPool::events` reports. A waiter that is handed an object is woken, then checks it out.
+#[cfg(feature = "stream")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolEvent {
+    CheckedOut,
+    Released,
+    WaiterQueued,
+    WaiterWoken,
+}
+
+// Events a subscriber hasn't read yet, later ones are dropped for it.
+#[cfg(feature = "stream")]
+const EVENT_BUFFER: usize = 64;
+
+struct PoolWaiter {
+    waiter_id: u64,
+    future: NotifyFuture<()>,
+    // routing data of `get_with_key`, read by `ObjectGuard::release_by_key`
+    key: Option<Box<dyn Any + Send>>,
+}
+
+struct ObjectPoolState<T> {
+    free_list: Vec<T>,
+    waiter_list: VecDeque<PoolWaiter>,
+    // objects released to a waiter that hasn't woken up to collect them yet
+    handed_list: HashMap<u64, T>,
+    waiter_seq: u64,
+    capacity: usize,
+    max_capacity: Option<usize>,
+    policy: WaitPolicy,
+    closed: bool,
+    // `close` calls waiting for the checked-out objects to come back
+    close_list: Vec<NotifyFuture<()>>,
+    #[cfg(feature = "stream")]
+    event_list: Vec<async_channel::Sender<PoolEvent>>,
+}
+
+impl <T> ObjectPoolState<T> {
+    // Hands a free object to the first waiter, or stores it if nobody waits.
+    fn put(&mut self, obj: T) {
+        let next = match self.policy {
+            WaitPolicy::Fifo => self.waiter_list.pop_front(),
+            WaitPolicy::Lifo => self.waiter_list.pop_back(),
+        };
+        self.hand_or_store(obj, next);
+    }
+
+    fn hand_or_store(&mut self, obj: T, waiter: Option<PoolWaiter>) {
+        if let Some(waiter) = waiter {
+            self.handed_list.insert(waiter.waiter_id, obj);
+            waiter.future.set_complete(());
+            #[cfg(feature = "stream")]
+            self.emit(PoolEvent::WaiterWoken);
+        } else {
+            self.free_list.push(obj);
+            self.wake_closers();
+        }
+    }
+
+    // Never waits for a subscriber: a full channel drops the event, a closed one is removed.
+    #[cfg(feature = "stream")]
+    fn emit(&mut self, event: PoolEvent) {
+        self.event_list.retain(|sender| !matches!(sender.try_send(event), Err(async_channel::TrySendError::Closed(_))));
+    }
+
+    fn wake_closers(&mut self) {
+        if self.closed && self.free_list.len() == self.capacity {
+            for future in self.close_list.drain(..) {
+                future.set_complete(());
+            }
+        }
+    }
+
+    // Counts one more object towards the capacity, unless the pool is closed or full.
+    fn grow(&mut self) -> Result<(), PoolError> {
+        if self.closed {
+            return Err(PoolError::Closed);
+        }
+        if self.max_capacity.is_some_and(|max_capacity| self.capacity >= max_capacity) {
+            return Err(PoolError::Full);
+        }
+        self.capacity += 1;
+        Ok(())
+    }
+
+    // Gives the objects to `close` once they are all back, the pool owns none afterwards.
+    fn take_closed(&mut self) -> Option<Vec<T>> {
+        if self.free_list.len() != self.capacity {
+            return None;
+        }
+        self.capacity = 0;
+        Some(std::mem::take(&mut self.free_list))
+    }
+}
+
+// A bounded set of reusable objects. `get` hands out any free object and waits in FIFO order while all
+// of them are checked out, dropping the returned guard puts the object back.
+pub struct ObjectPool<T> {
+    state: Arc<Mutex<ObjectPoolState<T>>>,
+}
+
+impl <T> Clone for ObjectPool<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone()
+        }
+    }
+}
+
+pub struct ObjectPoolBuilder<T> {
+    policy: WaitPolicy,
+    max_capacity: Option<usize>,
+    _object: std::marker::PhantomData<T>,
+}
+
+impl <T> ObjectPoolBuilder<T> {
+    pub fn policy(mut self, policy: WaitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // Caps how many objects `provide` and `ObjectGuard::try_release_into` can grow the pool to, the
+    // objects given to `build` count towards it.
+    pub fn max_capacity(mut self, max_capacity: usize) -> Self {
+        self.max_capacity = Some(max_capacity);
+        self
+    }
+
+    pub fn build(self, objs: Vec<T>) -> ObjectPool<T> {
+        ObjectPool {
+            state: Arc::new(Mutex::new(ObjectPoolState {
+                capacity: objs.len(),
+                max_capacity: self.max_capacity,
+                free_list: objs,
+                waiter_list: VecDeque::new(),
+                handed_list: HashMap::new(),
+                waiter_seq: 0,
+                policy: self.policy,
+                closed: false,
+                close_list: Vec::new(),
+                #[cfg(feature = "stream")]
+                event_list: Vec::new(),
+            }))
+        }
+    }
+}
+
+// A handle that doesn't keep the pool alive, e.g. for caches the pool's objects refer back to. Guards
+// still hold the pool, so it lives until the last clone and the last guard are dropped.
+pub struct WeakObjectPool<T> {
+    state: Weak<Mutex<ObjectPoolState<T>>>,
+}
+
+impl <T> Clone for WeakObjectPool<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone()
+        }
+    }
+}
+
+impl <T> std::fmt::Debug for WeakObjectPool<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WeakObjectPool").field("alive", &(self.state.strong_count() > 0)).finish()
+    }
+}
+
+impl <T> WeakObjectPool<T> {
+    pub fn upgrade(&self) -> Option<ObjectPool<T>> {
+        Some(ObjectPool {
+            state: self.state.upgrade()?
+        })
+    }
+}
+
+impl <T> std::fmt::Debug for ObjectPool<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock_recover();
+        f.debug_struct("ObjectPool")
+            .field("free", &state.free_list.len())
+            .field("waiting", &state.waiter_list.len())
+            .finish()
+    }
+}
+
+impl <T> ObjectPool<T> {
+    pub fn new(objs: Vec<T>) -> Self {
+        Self::with_policy(objs, WaitPolicy::Fifo)
+    }
+
+    pub fn with_policy(objs: Vec<T>, policy: WaitPolicy) -> Self {
+        Self::builder().policy(policy).build(objs)
+    }
+
+    pub fn builder() -> ObjectPoolBuilder<T> {
+        ObjectPoolBuilder {
+            policy: WaitPolicy::Fifo,
+            max_capacity: None,
+            _object: std::marker::PhantomData,
+        }
+    }
+
+    pub fn downgrade(&self) -> WeakObjectPool<T> {
+        WeakObjectPool {
+            state: Arc::downgrade(&self.state)
+        }
+    }
+
+    // A pool without objects yet, every `get` waits until `provide` adds one.
+    pub fn empty() -> Self {
+        Self::new(Vec::new())
+    }
+
+    // Adds an object, e.g. a handle that wasn't ready when the pool was made. The first waiter gets it,
+    // or it is stored until the next `get`. A pool that already has objects just grows by one. A closed
+    // pool, or one at its `max_capacity`, gives the object back.
+    pub fn provide(&self, obj: T) -> Result<(), T> {
+        let mut state = self.state.lock_recover();
+        if state.grow().is_err() {
+            return Err(obj);
+        }
+        state.put(obj);
+        Ok(())
+    }
+
+    // Fails with `PoolError::Closed` once `close` was called, waiters queued at that point fail as well.
+    pub async fn get(&self) -> Result<ObjectGuard<T>, PoolError> {
+        Ok(self.get_or_init_with(None::<fn() -> T>, None).await?.0)
+    }
+
+    // A `get` that also tells where the object came from, e.g. to health-check connections that sat
+    // in the pool but not ones another task was just using.
+    pub async fn get_entry(&self) -> Result<(ObjectGuard<T>, Provenance), PoolError> {
+        self.get_or_init_with(None::<fn() -> T>, None).await
+    }
+
+    // A `get` whose waiter carries `key`, e.g. its priority. Plain releases ignore it and follow the pool's
+    // `WaitPolicy`, `ObjectGuard::release_by_key` hands the object to the waiter with the best key.
+    pub async fn get_with_key(&self, key: impl Any + Send) -> Result<ObjectGuard<T>, PoolError> {
+        Ok(self.get_or_init_with(None::<fn() -> T>, Some(Box::new(key))).await?.0)
+    }
+
+    // Creates the object with `init` if the pool has none yet, and otherwise behaves like `get`. The
+    // choice is made under the state lock, so `init` runs at most once however many first callers race,
+    // the others wait for the object it made. `init` runs under that lock, it should be quick.
+    pub async fn get_or_init(&self, init: impl FnOnce() -> T) -> Result<ObjectGuard<T>, PoolError> {
+        Ok(self.get_or_init_with(Some(init), None).await?.0)
+    }
+
+    async fn get_or_init_with(&self, init: Option<impl FnOnce() -> T>, key: Option<Box<dyn Any + Send>>)
+                              -> Result<(ObjectGuard<T>, Provenance), PoolError> {
+        let waiter = {
+            let mut state = self.state.lock_recover();
+            if state.closed {
+                return Err(PoolError::Closed);
+            }
+            if let Some(obj) = state.free_list.pop() {
+                #[cfg(feature = "stream")]
+                state.emit(PoolEvent::CheckedOut);
+                return Ok((ObjectGuard::new(self.clone(), obj), Provenance::Stored));
+            }
+            if let (0, Some(init)) = (state.capacity, init) {
+                let obj = init();
+                state.capacity = 1;
+                #[cfg(feature = "stream")]
+                state.emit(PoolEvent::CheckedOut);
+                return Ok((ObjectGuard::new(self.clone(), obj), Provenance::Stored));
+            }
+            let waiter_id = state.waiter_seq;
+            state.waiter_seq += 1;
+            let future = NotifyFuture::new();
+            state.waiter_list.push_back(PoolWaiter {
+                waiter_id,
+                future: future.clone(),
+                key,
+            });
+            #[cfg(feature = "stream")]
+            state.emit(PoolEvent::WaiterQueued);
+            PendingObject {
+                pool: self,
+                waiter_id,
+                future,
+                acquired: false,
+            }
+        };
+        let obj = waiter.wait().await.ok_or(PoolError::Closed)?;
+        Ok((ObjectGuard::new(self.clone(), obj), Provenance::Handoff))
+    }
+
+    // Takes a free object or returns None right away, without queueing behind the waiters. There are
+    // only free objects while nobody waits, so it can't overtake a waiting `get`. A closed pool lends nothing.
+    pub fn try_get(&self) -> Option<ObjectGuard<T>> {
+        let mut state = self.state.lock_recover();
+        if state.closed {
+            return None;
+        }
+        let obj = state.free_list.pop()?;
+        #[cfg(feature = "stream")]
+        state.emit(PoolEvent::CheckedOut);
+        drop(state);
+        Some(ObjectGuard::new(self.clone(), obj))
+    }
+
+    // Runs `f` on the free object the next `get` would take, without checking it out, or returns None while
+    // all of them are checked out. `f` runs under the state lock and blocks every `get` and release of the
+    // pool meanwhile, it must be fast and must not use the pool.
+    pub fn peek<R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.state.lock_recover().free_list.last().map(f)
+    }
+
+    // A view lending the objects as `U`: its guards convert them with `to` on checkout and back with `from`
+    // when they drop, the objects themselves stay in this pool. If a conversion panics the object is lost
+    // and the pool shrinks by one.
+    pub fn map<U>(self, to: impl Fn(T) -> U + Send + Sync + 'static, from: impl Fn(U) -> T + Send + Sync + 'static)
+                  -> ObjectPoolView<T, U> {
+        ObjectPoolView {
+            pool: self,
+            conversion: Arc::new(Conversion {
+                to: Box::new(to),
+                from: Box::new(from),
+            }),
+        }
+    }
+
+    // A stream of what happens to the pool, for debugging it in production. It never slows the pool down:
+    // a subscriber that falls behind by too many events misses the later ones.
+    #[cfg(feature = "stream")]
+    pub fn events(&self) -> impl futures_core::Stream<Item = PoolEvent> + Send + Unpin {
+        let (sender, receiver) = async_channel::bounded(EVENT_BUFFER);
+        self.state.lock_recover().event_list.push(sender);
+        receiver
+    }
+
+    // A `get` that gives up after `timeout`. The timed-out waiter leaves the queue, or passes on the object
+    // if it was handed one just as the timeout fired.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn get_timeout(&self, timeout: Duration) -> Result<Option<ObjectGuard<T>>, PoolError> {
+        timer::timeout(self.get(), timer::sleep(timeout)).await.transpose()
+    }
+
+    // Stops lending: later `get`s and the queued waiters fail with `PoolError::Closed`. Resolves with the
+    // pool's objects once every checked-out one is back, right away if none is out. The pool owns no
+    // objects afterwards, a second `close` resolves with an empty list.
+    pub async fn close(&self) -> Vec<T> {
+        let future = {
+            let mut state = self.state.lock_recover();
+            state.closed = true;
+            for waiter in state.waiter_list.drain(..) {
+                waiter.future.set_complete(());
+            }
+            if let Some(objs) = state.take_closed() {
+                return objs;
+            }
+            let future = NotifyFuture::new();
+            state.close_list.push(future.clone());
+            future
+        };
+        future.await;
+        self.state.lock_recover().take_closed().unwrap_or_default()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock_recover().closed
+    }
+
+    // Snapshots for health checks, they may be outdated as soon as they return. Objects on their way to
+    // a waiter count as in use.
+    pub fn available(&self) -> usize {
+        self.state.lock_recover().free_list.len()
+    }
+
+    pub fn in_use(&self) -> usize {
+        let state = self.state.lock_recover();
+        state.capacity - state.free_list.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.state.lock_recover().capacity
+    }
+
+    fn release(&self, obj: T) {
+        let mut state = self.state.lock_recover();
+        #[cfg(feature = "stream")]
+        state.emit(PoolEvent::Released);
+        state.put(obj);
+    }
+
+    // The object of a checked-out guard is gone, e.g. a conversion of a view panicked with it.
+    fn forget_object(&self) {
+        let mut state = self.state.lock_recover();
+        state.capacity -= 1;
+        state.wake_closers();
+    }
+
+    // A dropped waiter leaves the queue, or gives back the object that was already handed to it.
+    fn cancel_wait(&self, waiter_id: u64) {
+        let mut state = self.state.lock_recover();
+        let pos = state.waiter_list.iter().position(|waiter| waiter.waiter_id == waiter_id);
+        if let Some(pos) = pos {
+            state.waiter_list.remove(pos);
+            return;
+        }
+        if let Some(obj) = state.handed_list.remove(&waiter_id) {
+            state.put(obj);
+        }
+    }
+}
+
+struct PendingObject<'a, T> {
+    pool: &'a ObjectPool<T>,
+    waiter_id: u64,
+    future: NotifyFuture<()>,
+    acquired: bool,
+}
+
+impl <'a, T> PendingObject<'a, T> {
+    // None when the waiter was woken by `close` instead of a released object.
+    async fn wait(mut self) -> Option<T> {
+        self.future.clone().await;
+        self.acquired = true;
+        let mut state = self.pool.state.lock_recover();
+        let obj = state.handed_list.remove(&self.waiter_id);
+        #[cfg(feature = "stream")]
+        if obj.is_some() {
+            state.emit(PoolEvent::CheckedOut);
+        }
+        obj
+    }
+}
+
+impl <'a, T> Drop for PendingObject<'a, T> {
+    fn drop(&mut self) {
+        if !self.acquired {
+            self.pool.cancel_wait(self.waiter_id);
+        }
+    }
+}
+
+// Guards keep their pool alive through a clone, so they are 'static and can be moved into a spawned
+// task (they are Send when T is), the object goes back to the pool wherever the guard drops.
+pub struct ObjectGuard<T> {
+    pool: ObjectPool<T>,
+    obj: Option<T>,
+}
+
+impl <T> ObjectGuard<T> {
+    fn new(pool: ObjectPool<T>, obj: T) -> Self {
+        Self {
+            pool,
+            obj: Some(obj)
+        }
+    }
+
+    // Makes the object go back to `target` instead of the pool it came from, e.g. to rebalance pools. It
+    // counts towards `target` from now on, which fails with `PoolError::Closed` or `PoolError::Full`
+    // without changing anything if it can't take it.
+    pub fn try_release_into(&mut self, target: &ObjectPool<T>) -> Result<(), PoolError> {
+        if Arc::ptr_eq(&self.pool.state, &target.state) {
+            return Ok(());
+        }
+        target.state.lock_recover().grow()?;
+        let source = std::mem::replace(&mut self.pool, target.clone());
+        let mut state = source.state.lock_recover();
+        state.capacity -= 1;
+        // a `close` of the old pool may only have waited for this object
+        state.wake_closers();
+        Ok(())
+    }
+
+    // Releases the object to the waiter whose `get_with_key` key of type `K` ranks highest by `cmp`, the
+    // earliest of equal ones. Waiters without such a key rank below, with none of them it is a plain drop.
+    pub fn release_by_key<K: 'static>(mut self, cmp: impl Fn(&K, &K) -> Ordering) {
+        let obj = self.obj.take().unwrap();
+        let mut state = self.pool.state.lock_recover();
+        #[cfg(feature = "stream")]
+        state.emit(PoolEvent::Released);
+        let mut best: Option<(usize, &K)> = None;
+        for (pos, waiter) in state.waiter_list.iter().enumerate() {
+            let Some(key) = waiter.key.as_ref().and_then(|key| key.downcast_ref::<K>()) else {
+                continue;
+            };
+            if best.is_none_or(|(_, best_key)| cmp(key, best_key) == Ordering::Greater) {
+                best = Some((pos, key));
+            }
+        }
+        match best.map(|(pos, _)| pos) {
+            Some(pos) => {
+                let waiter = state.waiter_list.remove(pos);
+                state.hand_or_store(obj, waiter);
+            }
+            None => state.put(obj),
+        }
+    }
+
+    // Swaps in a new object, e.g. a reconnected one, which is what goes back to the pool on drop.
+    pub fn replace(&mut self, obj: T) -> T {
+        std::mem::replace(self.obj.as_mut().unwrap(), obj)
+    }
+
+    // Narrows the guard to a part of the object, the whole object still goes back to the pool when the
+    // mapped guard drops.
+    pub fn map<U: ?Sized, F>(mut self, f: F) -> MappedObjectGuard<T, U>
+        where F: FnOnce(&mut T) -> &mut U {
+        let obj = Box::into_raw(Box::new(self.obj.take().unwrap()));
+        // the object is boxed so the projection stays valid when the mapped guard moves
+        let unwind_guard = BoxedObjectRelease {
+            pool: &self.pool,
+            obj,
+        };
+        let value = f(unsafe { &mut *obj }) as *mut U;
+        std::mem::forget(unwind_guard);
+        MappedObjectGuard {
+            pool: self.pool.clone(),
+            obj,
+            value,
+        }
+    }
+}
+
+impl <T> std::fmt::Debug for ObjectGuard<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObjectGuard").field("has_object", &self.obj.is_some()).finish()
+    }
+}
+
+impl <T> Deref for ObjectGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.obj.as_ref().unwrap()
+    }
+}
+
+impl <T> DerefMut for ObjectGuard<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.obj.as_mut().unwrap()
+    }
+}
+
+impl <T> Drop for ObjectGuard<T> {
+    fn drop(&mut self) {
+        if let Some(obj) = self.obj.take() {
+            self.pool.release(obj);
+        }
+    }
+}
+
+// Gives the boxed object back to the pool if the closure of `map` panics.
+struct BoxedObjectRelease<'a, T> {
+    pool: &'a ObjectPool<T>,
+    obj: *mut T,
+}
+
+impl <'a, T> Drop for BoxedObjectRelease<'a, T> {
+    fn drop(&mut self) {
+        let obj = unsafe { Box::from_raw(self.obj) };
+        self.pool.release(*obj);
+    }
+}
+
+pub struct MappedObjectGuard<T, U: ?Sized> {
+    pool: ObjectPool<T>,
+    obj: *mut T,
+    value: *mut U,
+}
+
+// The guard owns the boxed object exclusively, the raw pointers are only there to keep the projection.
+unsafe impl <T: Send, U: ?Sized + Send> Send for MappedObjectGuard<T, U> {}
+unsafe impl <T: Send, U: ?Sized + Sync> Sync for MappedObjectGuard<T, U> {}
+
+impl <T, U: ?Sized> std::fmt::Debug for MappedObjectGuard<T, U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MappedObjectGuard").finish_non_exhaustive()
+    }
+}
+
+impl <T, U: ?Sized> Deref for MappedObjectGuard<T, U> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.value }
+    }
+}
+
+impl <T, U: ?Sized> DerefMut for MappedObjectGuard<T, U> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.value }
+    }
+}
+
+impl <T, U: ?Sized> Drop for MappedObjectGuard<T, U> {
+    fn drop(&mut self) {
+        let obj = unsafe { Box::from_raw(self.obj) };
+        self.pool.release(*obj);
+    }
+}
+
+struct Conversion<T, U> {
+    to: Box<dyn Fn(T) -> U + Send + Sync>,
+    from: Box<dyn Fn(U) -> T + Send + Sync>,
+}
+
+pub struct ObjectPoolView<T, U> {
+    pool: ObjectPool<T>,
+    conversion: Arc<Conversion<T, U>>,
+}
+
+impl <T, U> Clone for ObjectPoolView<T, U> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            conversion: self.conversion.clone(),
+        }
+    }
+}
+
+impl <T, U> std::fmt::Debug for ObjectPoolView<T, U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObjectPoolView").field("pool", &self.pool).finish()
+    }
+}
+
+impl <T, U> ObjectPoolView<T, U> {
+    pub fn pool(&self) -> &ObjectPool<T> {
+        &self.pool
+    }
+
+    pub async fn get(&self) -> Result<ViewGuard<T, U>, PoolError> {
+        let guard = self.pool.get().await?;
+        Ok(self.convert(guard))
+    }
+
+    pub fn try_get(&self) -> Option<ViewGuard<T, U>> {
+        Some(self.convert(self.pool.try_get()?))
+    }
+
+    fn convert(&self, mut guard: ObjectGuard<T>) -> ViewGuard<T, U> {
+        let obj = guard.obj.take().unwrap();
+        let lost = LostObject {
+            pool: &self.pool,
+        };
+        let value = (self.conversion.to)(obj);
+        std::mem::forget(lost);
+        ViewGuard {
+            pool: self.pool.clone(),
+            conversion: self.conversion.clone(),
+            value: Some(value),
+        }
+    }
+}
+
+// Takes the object out of the pool's count if a conversion panics with it.
+struct LostObject<'a, T> {
+    pool: &'a ObjectPool<T>,
+}
+
+impl <'a, T> Drop for LostObject<'a, T> {
+    fn drop(&mut self) {
+        self.pool.forget_object();
+    }
+}
+
+pub struct ViewGuard<T, U> {
+    pool: ObjectPool<T>,
+    conversion: Arc<Conversion<T, U>>,
+    value: Option<U>,
+}
+
+impl <T, U> std::fmt::Debug for ViewGuard<T, U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ViewGuard").finish_non_exhaustive()
+    }
+}
+
+impl <T, U> Deref for ViewGuard<T, U> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl <T, U> DerefMut for ViewGuard<T, U> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl <T, U> Drop for ViewGuard<T, U> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            let lost = LostObject {
+                pool: &self.pool,
+            };
+            let obj = (self.conversion.from)(value);
+            std::mem::forget(lost);
+            self.pool.release(obj);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use crate::{ObjectPool, PoolError, Provenance, WaitPolicy};
+    use crate::sync::{assert_send, assert_send_sync};
+
+    #[test]
+    fn test_pool() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1, 2]);
+            let running = Arc::new(AtomicUsize::new(0));
+            let max_running = Arc::new(AtomicUsize::new(0));
+            let mut tasks = Vec::new();
+            for _ in 0..4 {
+                let pool = pool.clone();
+                let running = running.clone();
+                let max_running = max_running.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    let obj = pool.get().await.unwrap();
+                    let count = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(count, Ordering::SeqCst);
+                    async_std::task::sleep(Duration::from_millis(100)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    *obj
+                }));
+            }
+            let mut used = Vec::new();
+            for task in tasks {
+                used.push(task.await);
+            }
+            assert_eq!(max_running.load(Ordering::SeqCst), 2);
+            assert_eq!(used.iter().filter(|v| **v == 1).count(), 2);
+            assert_eq!(used.iter().filter(|v| **v == 2).count(), 2);
+
+            // a cancelled waiter doesn't swallow the object, whether it was handed one before it was dropped
+            let obj = pool.get().await.unwrap();
+            let obj2 = pool.get().await.unwrap();
+            let mut waiter = Box::pin(pool.get());
+            assert!(async_std::future::timeout(Duration::from_millis(100), &mut waiter).await.is_err());
+            drop(obj);
+            drop(waiter);
+            let obj = async_std::future::timeout(Duration::from_secs(1), pool.get()).await.unwrap().unwrap();
+            // or was still queued
+            let mut waiter = Box::pin(pool.get());
+            assert!(async_std::future::timeout(Duration::from_millis(100), &mut waiter).await.is_err());
+            drop(waiter);
+            assert_eq!(format!("{:?}", pool), "ObjectPool { free: 0, waiting: 0 }");
+            drop(obj);
+            drop(obj2);
+            assert_eq!(pool.available(), 2);
+        });
+    }
+
+    async fn handoff_order(pool: ObjectPool<u32>) -> Vec<&'static str> {
+        let obj = pool.get().await.unwrap();
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut waiters = Vec::new();
+        for name in ["A", "B", "C"] {
+            let pool = pool.clone();
+            let order = order.clone();
+            waiters.push(async_std::task::spawn(async move {
+                let _obj = pool.get().await.unwrap();
+                order.lock().unwrap().push(name);
+            }));
+            // queue the waiters one after another
+            async_std::task::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(format!("{:?}", pool), "ObjectPool { free: 0, waiting: 3 }");
+        drop(obj);
+        for waiter in waiters {
+            waiter.await;
+        }
+        let order = order.lock().unwrap().clone();
+        order
+    }
+
+    #[test]
+    fn test_fifo_waiters() {
+        async_std::task::block_on(async {
+            assert_eq!(handoff_order(ObjectPool::new(vec![1])).await, vec!["A", "B", "C"]);
+        });
+    }
+
+    #[test]
+    fn test_wait_policy() {
+        async_std::task::block_on(async {
+            assert_eq!(handoff_order(ObjectPool::with_policy(vec![1], WaitPolicy::Fifo)).await, vec!["A", "B", "C"]);
+            assert_eq!(handoff_order(ObjectPool::with_policy(vec![1], WaitPolicy::Lifo)).await, vec!["C", "B", "A"]);
+        });
+    }
+
+    #[test]
+    fn test_provide() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::empty();
+            assert!(pool.try_get().is_none());
+            let waiter = {
+                let pool = pool.clone();
+                async_std::task::spawn(async move { *pool.get().await.unwrap() })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(format!("{:?}", pool), "ObjectPool { free: 0, waiting: 1 }");
+            pool.provide(1).unwrap();
+            assert_eq!(waiter.await, 1);
+
+            // providing to a pool that has an object adds a second one
+            pool.provide(2).unwrap();
+            assert_eq!((pool.available(), pool.capacity()), (2, 2));
+            let _obj1 = pool.get().await.unwrap();
+            let _obj2 = pool.get().await.unwrap();
+            assert_eq!(pool.in_use(), 2);
+
+            let pool = ObjectPool::builder().max_capacity(1).build(vec![1]);
+            assert_eq!(pool.provide(2), Err(2));
+            assert_eq!(pool.capacity(), 1);
+        });
+    }
+
+    #[test]
+    fn test_release_into() {
+        async_std::task::block_on(async {
+            let pool_a = ObjectPool::new(vec![1]);
+            let pool_b = ObjectPool::builder().max_capacity(2).build(vec![2]);
+            let obj_b = pool_b.get().await.unwrap();
+            let waiter = {
+                let pool_b = pool_b.clone();
+                async_std::task::spawn(async move { *pool_b.get().await.unwrap() })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            let mut obj = pool_a.get().await.unwrap();
+            obj.try_release_into(&pool_b).unwrap();
+            assert_eq!((pool_a.capacity(), pool_b.capacity()), (0, 2));
+            // the object wakes the waiter of pool B
+            drop(obj);
+            assert_eq!(waiter.await, 1);
+            assert_eq!(pool_a.available(), 0);
+
+            // pool B is full now, and a closed pool takes nothing
+            let mut obj = obj_b;
+            let pool_c = ObjectPool::new(vec![3]);
+            let mut obj_c = pool_c.get().await.unwrap();
+            assert_eq!(obj_c.try_release_into(&pool_b), Err(PoolError::Full));
+            drop(obj_c);
+            assert_eq!(pool_c.close().await, vec![3]);
+            assert_eq!(obj.try_release_into(&pool_c), Err(PoolError::Closed));
+            drop(obj);
+            assert_eq!(pool_b.available(), 2);
+        });
+    }
+
+    #[test]
+    fn test_release_into_closing_pool() {
+        async_std::task::block_on(async {
+            let pool_a = ObjectPool::new(vec![1, 2]);
+            let pool_b = ObjectPool::empty();
+            let mut obj = pool_a.get().await.unwrap();
+            let closing = {
+                let pool_a = pool_a.clone();
+                async_std::task::spawn(async move { pool_a.close().await })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            // the close of pool A only waited for the object that moved away
+            obj.try_release_into(&pool_b).unwrap();
+            assert_eq!(closing.await, vec![3 - *obj]);
+            let moved = *obj;
+            drop(obj);
+            assert_eq!(*pool_b.try_get().unwrap(), moved);
+        });
+    }
+
+    #[test]
+    fn test_weak_pool() {
+        struct Tracked(Arc<AtomicUsize>);
+        impl Drop for Tracked {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        async_std::task::block_on(async {
+            let dropped = Arc::new(AtomicUsize::new(0));
+            let pool = ObjectPool::new(vec![Tracked(dropped.clone()), Tracked(dropped.clone())]);
+            let weak = pool.downgrade();
+            let obj = weak.upgrade().unwrap().get().await.unwrap();
+            assert_eq!(weak.upgrade().unwrap().in_use(), 1);
+            drop(pool);
+            // the guard still holds the pool
+            assert!(weak.upgrade().is_some());
+            drop(obj);
+            assert!(weak.upgrade().is_none());
+            assert_eq!(format!("{:?}", weak), "WeakObjectPool { alive: false }");
+            assert_eq!(dropped.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn test_get_or_init() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::empty();
+            let inits = Arc::new(AtomicUsize::new(0));
+            let mut tasks = Vec::new();
+            for _ in 0..2 {
+                let pool = pool.clone();
+                let inits = inits.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    let obj = pool.get_or_init(|| {
+                        inits.fetch_add(1, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(20));
+                        7
+                    }).await.unwrap();
+                    async_std::task::sleep(Duration::from_millis(20)).await;
+                    *obj
+                }));
+            }
+            for task in tasks {
+                assert_eq!(task.await, 7);
+            }
+            assert_eq!(inits.load(Ordering::SeqCst), 1);
+            assert_eq!(pool.capacity(), 1);
+            // later callers reuse the object
+            assert_eq!(*pool.get_or_init(|| 8).await.unwrap(), 7);
+        });
+    }
+
+    #[test]
+    fn test_try_get() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1]);
+            let obj = pool.get().await.unwrap();
+            assert!(pool.try_get().is_none());
+            assert_eq!(format!("{:?}", pool), "ObjectPool { free: 0, waiting: 0 }");
+            drop(obj);
+            assert_eq!(*pool.try_get().unwrap(), 1);
+        });
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_get_timeout() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1]);
+            let obj = pool.get().await.unwrap();
+            assert!(pool.get_timeout(Duration::from_millis(50)).await.unwrap().is_none());
+            assert_eq!(format!("{:?}", pool), "ObjectPool { free: 0, waiting: 0 }");
+            // the timed-out borrower doesn't consume the object released after it gave up
+            let waiter = {
+                let pool = pool.clone();
+                async_std::task::spawn(async move { *pool.get_timeout(Duration::from_secs(1)).await.unwrap().unwrap() })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            drop(obj);
+            assert_eq!(waiter.await, 1);
+            assert_eq!(pool.available(), 1);
+        });
+    }
+
+    #[test]
+    fn test_map_guard() {
+        struct Conn {
+            id: u32,
+            requests: Vec<u32>,
+        }
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![Conn { id: 1, requests: Vec::new() }]);
+            let mut requests = pool.get().await.unwrap().map(|conn| &mut conn.requests);
+            requests.push(10);
+            let moved = requests;
+            assert_eq!(*moved, vec![10]);
+            drop(moved);
+
+            let conn = pool.get().await.unwrap();
+            assert_eq!(conn.id, 1);
+            assert_eq!(conn.requests, vec![10]);
+        });
+    }
+
+    #[test]
+    fn test_map_guard_panic() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1]);
+            let obj = pool.get().await.unwrap();
+            let ret = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                obj.map(|_| -> &mut u32 { panic!("panic while mapping") });
+            }));
+            assert!(ret.is_err());
+            // the object went back to the pool anyway
+            assert_eq!((pool.available(), pool.in_use()), (1, 0));
+            let obj = async_std::future::timeout(Duration::from_secs(1), pool.get()).await.unwrap().unwrap();
+            assert_eq!(*obj, 1);
+        });
+    }
+
+    #[test]
+    fn test_replace() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec!["old".to_string()]);
+            let mut obj = pool.get().await.unwrap();
+            assert_eq!(obj.replace("new".to_string()), "old");
+            assert_eq!(*obj, "new");
+            drop(obj);
+            assert_eq!(*pool.get().await.unwrap(), "new");
+        });
+    }
+
+    #[test]
+    fn test_debug() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1, 2]);
+            let obj = pool.get().await.unwrap();
+            assert_eq!(format!("{:?}", pool), "ObjectPool { free: 1, waiting: 0 }");
+            assert_eq!(format!("{:?}", obj), "ObjectGuard { has_object: true }");
+            let mapped = obj.map(|v| v);
+            assert_eq!(format!("{:?}", mapped), "MappedObjectGuard { .. }");
+        });
+    }
+
+    #[test]
+    fn test_move_guard_to_task() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1]);
+            let mut obj = pool.get().await.unwrap();
+            async_std::task::spawn(async move {
+                *obj += 1;
+                drop(obj);
+            }).await;
+            let obj = async_std::future::timeout(Duration::from_secs(1), pool.get()).await.unwrap().unwrap();
+            assert_eq!(*obj, 2);
+        });
+    }
+
+    // parking_lot mutexes don't poison
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_poisoned_state_mutex() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1]);
+            let state = pool.state.clone();
+            let _ = std::thread::spawn(move || {
+                let _state = state.lock().unwrap();
+                panic!("panic while holding the pool state");
+            }).join();
+            assert!(pool.state.is_poisoned());
+            let obj = pool.get().await.unwrap();
+            assert_eq!(*obj, 1);
+            drop(obj);
+            assert!(async_std::future::timeout(Duration::from_secs(1), pool.get()).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_release_by_key() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1]);
+            let obj = pool.get().await.unwrap();
+            let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let mut waiters = Vec::new();
+            for (name, priority) in [("low", 1u32), ("high", 5u32)] {
+                let pool = pool.clone();
+                let order = order.clone();
+                waiters.push(async_std::task::spawn(async move {
+                    let _obj = pool.get_with_key(priority).await.unwrap();
+                    order.lock().unwrap().push(name);
+                }));
+                async_std::task::sleep(Duration::from_millis(20)).await;
+            }
+            // the later waiter goes first, the plain drop of its guard then serves the other one
+            obj.release_by_key::<u32>(|a, b| a.cmp(b));
+            for waiter in waiters {
+                waiter.await;
+            }
+            assert_eq!(*order.lock().unwrap(), vec!["high", "low"]);
+
+            // without keyed waiters it is a plain release
+            pool.get().await.unwrap().release_by_key::<u32>(|a, b| a.cmp(b));
+            assert_eq!(pool.available(), 1);
+        });
+    }
+
+    #[test]
+    fn test_peek() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec!["conn".to_string()]);
+            assert_eq!(pool.peek(|conn| conn.len()), Some(4));
+            assert_eq!(pool.available(), 1);
+            let obj = pool.get().await.unwrap();
+            assert_eq!(pool.peek(|conn| conn.len()), None);
+            drop(obj);
+            assert_eq!(pool.peek(|conn| conn.clone()), Some("conn".to_string()));
+        });
+    }
+
+    #[test]
+    fn test_map_pool() {
+        async_std::task::block_on(async {
+            let view = ObjectPool::new(vec![1]).map(|v: i32| v.to_string(), |s: String| s.parse().unwrap());
+            let mut obj = view.get().await.unwrap();
+            assert_eq!(*obj, "1");
+            obj.push('0');
+            assert!(view.try_get().is_none());
+            drop(obj);
+            // the view and the pool share the object
+            assert_eq!(*view.pool().get().await.unwrap(), 10);
+            assert_eq!(*view.get().await.unwrap(), "10");
+
+            // a conversion that panics loses the object
+            let view = ObjectPool::new(vec![-1]).map(|v: i32| u32::try_from(v).unwrap(), |v: u32| v as i32);
+            let ret = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| view.try_get()));
+            assert!(ret.is_err());
+            assert_eq!((view.pool().capacity(), view.pool().in_use()), (0, 0));
+            assert!(view.pool().close().await.is_empty());
+        });
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn test_events() {
+        use async_std::stream::StreamExt;
+        use crate::PoolEvent;
+        use crate::sync::LockRecover;
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1]);
+            let mut events = pool.events();
+            let obj = pool.get().await.unwrap();
+            let waiter = {
+                let pool = pool.clone();
+                async_std::task::spawn(async move { *pool.get().await.unwrap() })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            drop(obj);
+            assert_eq!(waiter.await, 1);
+            let mut seen = Vec::new();
+            for _ in 0..6 {
+                seen.push(events.next().await.unwrap());
+            }
+            assert_eq!(seen, vec![PoolEvent::CheckedOut, PoolEvent::WaiterQueued, PoolEvent::Released,
+                                  PoolEvent::WaiterWoken, PoolEvent::CheckedOut, PoolEvent::Released]);
+
+            // a subscriber that doesn't read doesn't hold up the pool, it misses the later events
+            for _ in 0..100 {
+                drop(pool.get().await.unwrap());
+            }
+            let mut missed = 0;
+            while let Ok(Some(_)) = async_std::future::timeout(Duration::from_millis(10), events.next()).await {
+                missed += 1;
+            }
+            assert_eq!(missed, 64);
+            // a dropped subscriber is forgotten
+            drop(events);
+            drop(pool.get().await.unwrap());
+            assert!(pool.state.lock_recover().event_list.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_get_entry() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1]);
+            let (obj, provenance) = pool.get_entry().await.unwrap();
+            assert_eq!(provenance, Provenance::Stored);
+            let waiter = {
+                let pool = pool.clone();
+                async_std::task::spawn(async move { pool.get_entry().await.map(|(obj, provenance)| (*obj, provenance)) })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            drop(obj);
+            assert_eq!(waiter.await, Ok((1, Provenance::Handoff)));
+            // the object the waiter released went back to the pool
+            assert_eq!(pool.get_entry().await.unwrap().1, Provenance::Stored);
+        });
+    }
+
+    #[test]
+    fn test_close() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1, 2]);
+            let obj = pool.get().await.unwrap();
+            let obj2 = pool.get().await.unwrap();
+            let waiter = {
+                let pool = pool.clone();
+                async_std::task::spawn(async move { pool.get().await.map(|obj| *obj) })
+            };
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            let closing = {
+                let pool = pool.clone();
+                async_std::task::spawn(async move { pool.close().await })
+            };
+            // the queued waiter fails instead of getting the released objects
+            assert_eq!(waiter.await, Err(PoolError::Closed));
+            assert!(pool.is_closed());
+            assert_eq!(pool.get().await.unwrap_err(), PoolError::Closed);
+            drop(obj);
+            assert!(pool.try_get().is_none());
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(pool.in_use(), 1);
+            drop(obj2);
+            let mut objs = closing.await;
+            objs.sort();
+            assert_eq!(objs, vec![1, 2]);
+            assert_eq!(pool.capacity(), 0);
+
+            // nothing checked out, it resolves right away
+            assert_eq!(ObjectPool::new(vec![3]).close().await, vec![3]);
+            assert!(pool.close().await.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_counts() {
+        async_std::task::block_on(async {
+            let pool = ObjectPool::new(vec![1, 2, 3]);
+            assert_eq!((pool.available(), pool.in_use(), pool.capacity()), (3, 0, 3));
+            let obj1 = pool.get().await.unwrap();
+            let obj2 = pool.get().await.unwrap();
+            assert_eq!((pool.available(), pool.in_use(), pool.capacity()), (1, 2, 3));
+            drop(obj1);
+            assert_eq!((pool.available(), pool.in_use(), pool.capacity()), (2, 1, 3));
+            drop(obj2);
+            assert_eq!((pool.available(), pool.in_use(), pool.capacity()), (3, 0, 3));
+        });
+    }
+
+    #[test]
+    fn test_auto_traits() {
+        assert_send_sync::<ObjectPool<Vec<u8>>>();
+        assert_send_sync::<crate::WeakObjectPool<Vec<u8>>>();
+        assert_send_sync::<crate::ObjectGuard<Vec<u8>>>();
+        assert_send_sync::<crate::MappedObjectGuard<Vec<u8>, [u8]>>();
+        assert_send_sync::<crate::ObjectPoolView<Vec<u8>, String>>();
+        assert_send_sync::<crate::ViewGuard<Vec<u8>, String>>();
+        // the future of `get` can be spawned on multi-threaded runtimes
+        let pool = ObjectPool::new(vec![1]);
+        assert_send(&pool.get());
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use crate::sync::{LockRecover, Mutex};
+use crate::{ObjectGuard, ObjectPool, PoolError};
+
+// Named `ObjectPool`s, for code that needs objects of several of them at once. `get_many` takes the
+// pools in sorted name order like `LockerManager::get_lockers`, so two callers asking for overlapping
+// sets in any order can't each hold an object the other waits for.
+pub struct ObjectRegistry<T> {
+    pools: Mutex<HashMap<String, ObjectPool<T>>>,
+}
+
+impl <T> Default for ObjectRegistry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <T> ObjectRegistry<T> {
+    pub fn new() -> Self {
+        Self {
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Registers `pool` under `name`, returning the pool it replaces.
+    pub fn insert(&self, name: impl Into<String>, pool: ObjectPool<T>) -> Option<ObjectPool<T>> {
+        self.pools.lock_recover().insert(name.into(), pool)
+    }
+
+    // Unregisters the pool, guards already taken from it still return their objects to it.
+    pub fn remove(&self, name: &str) -> Option<ObjectPool<T>> {
+        self.pools.lock_recover().remove(name)
+    }
+
+    pub fn pool(&self, name: &str) -> Option<ObjectPool<T>> {
+        self.pools.lock_recover().get(name).cloned()
+    }
+
+    pub async fn get(&self, name: &str) -> Result<ObjectGuard<T>, PoolError> {
+        let pool = self.pool(name).ok_or(PoolError::NotFound)?;
+        pool.get().await
+    }
+
+    // One object of each named pool, keyed by name. Duplicate names are merged, an unknown name fails
+    // with `PoolError::NotFound` before anything is taken, a closed pool with `PoolError::Closed` after
+    // the objects taken so far went back. The objects go back when the map drops.
+    pub async fn get_many(&self, names: &[&str]) -> Result<HashMap<String, ObjectGuard<T>>, PoolError> {
+        let mut names = names.to_vec();
+        names.sort();
+        names.dedup();
+        let pools = {
+            let pools = self.pools.lock_recover();
+            names.iter()
+                .map(|name| pools.get(*name).cloned().map(|pool| (name.to_string(), pool)))
+                .collect::<Option<Vec<_>>>()
+                .ok_or(PoolError::NotFound)?
+        };
+        let mut guards = HashMap::with_capacity(pools.len());
+        for (name, pool) in pools {
+            let guard = pool.get().await?;
+            guards.insert(name, guard);
+        }
+        Ok(guards)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::{ObjectPool, ObjectRegistry, PoolError};
+
+    #[test]
+    fn test_get_many() {
+        async_std::task::block_on(async {
+            let registry = ObjectRegistry::new();
+            registry.insert("a", ObjectPool::new(vec![1]));
+            registry.insert("b", ObjectPool::new(vec![2]));
+            let guards = registry.get_many(&["b", "a", "b"]).await.unwrap();
+            assert_eq!(guards.len(), 2);
+            assert_eq!(*guards["a"], 1);
+            assert_eq!(*guards["b"], 2);
+            assert!(registry.pool("a").unwrap().try_get().is_none());
+            drop(guards);
+            assert_eq!(registry.pool("a").unwrap().available(), 1);
+
+            assert_eq!(registry.get_many(&["a", "c"]).await.unwrap_err(), PoolError::NotFound);
+            assert_eq!(registry.pool("a").unwrap().available(), 1);
+            assert_eq!(registry.get("c").await.unwrap_err(), PoolError::NotFound);
+        });
+    }
+
+    #[test]
+    fn test_get_many_opposite_orders() {
+        async_std::task::block_on(async {
+            let registry = Arc::new(ObjectRegistry::new());
+            for name in ["a", "b", "c"] {
+                registry.insert(name, ObjectPool::new(vec![name.to_string()]));
+            }
+            let mut tasks = Vec::new();
+            for names in [vec!["a", "b", "c"], vec!["c", "b"]] {
+                let registry = registry.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    for _ in 0..50 {
+                        let guards = registry.get_many(&names).await.unwrap();
+                        assert_eq!(guards.len(), names.len());
+                        async_std::task::yield_now().await;
+                    }
+                }));
+            }
+            let done = async_std::future::timeout(Duration::from_secs(10), async {
+                for task in tasks {
+                    task.await;
+                }
+            }).await;
+            assert!(done.is_ok());
+        });
+    }
+}
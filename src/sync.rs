@@ -0,0 +1,36 @@
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::PoisonError;
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) use std::sync::{Mutex, MutexGuard};
+#[cfg(feature = "parking_lot")]
+pub(crate) use parking_lot::{Mutex, MutexGuard};
+
+// The crate's mutexes are never left inconsistent: nothing that can panic, like an observer or a
+// caller's code, runs while one is held, except where the state is already consistent, like the
+// initializer of `ObjectPool::get_or_init`. A poisoned mutex therefore never holds half-updated data,
+// and its data is used as is instead of failing every later call. With the parking_lot
+// feature the state is kept in `parking_lot` mutexes, which are cheaper and don't poison at all.
+pub(crate) trait LockRecover<T> {
+    fn lock_recover(&self) -> MutexGuard<'_, T>;
+}
+
+#[cfg(not(feature = "parking_lot"))]
+impl <T> LockRecover<T> for Mutex<T> {
+    fn lock_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(feature = "parking_lot")]
+impl <T> LockRecover<T> for Mutex<T> {
+    fn lock_recover(&self) -> MutexGuard<'_, T> {
+        self.lock()
+    }
+}
+
+// Compile-time checks of the auto traits of public types, shared by the test modules.
+#[cfg(test)]
+pub(crate) fn assert_send_sync<T: Send + Sync>() {}
+
+#[cfg(test)]
+pub(crate) fn assert_send<T: Send>(_: &T) {}
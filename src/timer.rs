@@ -0,0 +1,74 @@
+use std::future::Future;
+use std::pin::pin;
+use std::task::Poll;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use std::time::Duration;
+
+// Waiting itself only needs `NotifyFuture`, timers and the TTL expiry task are the only places a
+// runtime is needed. The `_with` variants of the timeout APIs take any sleep future and work without
+// one, the `Duration` ones only exist with the runtime picked by the opt-in `async-std` or `tokio` feature. With `tokio` the timeouts, TTL
+// expiry and `claim` windows go through `tokio::time` and `tokio::spawn`, so they panic unless they are
+// called inside a running tokio runtime with the time driver enabled.
+pub(crate) async fn timeout<F: Future>(future: F, sleep: impl Future<Output = ()>) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut sleep = pin!(sleep);
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        if sleep.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }).await
+}
+
+#[cfg(feature = "async-std")]
+pub(crate) fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    async_std::task::sleep(duration)
+}
+
+#[cfg(all(feature = "tokio", not(feature = "async-std")))]
+pub(crate) fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    tokio::time::sleep(duration)
+}
+
+#[cfg(feature = "async-std")]
+pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    async_std::task::spawn(future);
+}
+
+#[cfg(all(feature = "tokio", not(feature = "async-std")))]
+pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    tokio::spawn(future);
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::Locker;
+
+    #[test]
+    fn test_timeout_with() {
+        async_std::task::block_on(async {
+            let locker = Locker::get_locker("test_timeout_with").await;
+            let ret = Locker::get_locker_timeout_with("test_timeout_with",
+                                                      async_std::task::sleep(Duration::from_millis(100))).await;
+            assert!(ret.is_none());
+            drop(locker);
+            let ret = Locker::get_locker_timeout_with("test_timeout_with", std::future::pending()).await;
+            assert!(ret.is_some());
+        });
+    }
+
+    #[cfg(all(feature = "tokio", not(feature = "async-std")))]
+    #[test]
+    fn test_tokio_timeout() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            let _locker = Locker::get_locker("test_tokio_timeout").await;
+            let ret = Locker::get_locker_timeout("test_tokio_timeout", Duration::from_millis(100)).await;
+            assert!(ret.is_none());
+        });
+    }
+}
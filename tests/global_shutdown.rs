@@ -0,0 +1,20 @@
+// The global manager is shared by every test of a binary, so its shutdown is tested in a binary of its own.
+use std::time::Duration;
+use async_named_locker::{Locker, LockerError};
+
+#[test]
+fn test_global_shutdown() {
+    async_std::task::block_on(async {
+        let locker = Locker::get_locker_checked("test_global_shutdown").await.unwrap();
+        Locker::begin_shutdown();
+        assert!(Locker::is_shutting_down());
+        assert_eq!(Locker::get_locker_checked("test_global_shutdown_other").await.unwrap_err(), LockerError::ShuttingDown);
+        assert_eq!(Locker::get_read_locker_checked("test_global_shutdown_other").await.unwrap_err(), LockerError::ShuttingDown);
+        assert_eq!(Locker::get_upgradeable_locker_checked("test_global_shutdown_other").await.unwrap_err(), LockerError::ShuttingDown);
+        assert_eq!(Locker::get_semaphore_checked("test_global_shutdown_other", 2).await.unwrap_err(), LockerError::ShuttingDown);
+        assert!(Locker::try_get_locker("test_global_shutdown_other").is_none());
+        // the holder still releases cleanly
+        drop(locker);
+        async_std::future::timeout(Duration::from_secs(1), Locker::await_drained()).await.unwrap();
+    });
+}